@@ -0,0 +1,133 @@
+//! What the assembler reports about a source file it rejects.
+
+use std::fmt;
+use std::fs;
+
+/// A problem found in the source, `line` and `col` count from 1 and `len`
+/// is the width (in characters) of the offending token.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    /// the included file the problem is in, None for the source being assembled.
+    pub file: Option<String>,
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+    pub message: String,
+    /// how to fix it, when there's something useful to say.
+    pub hint: Option<String>,
+    /// the macro expansions and includes that led to the line, innermost first.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(line: usize, col: usize, len: usize, message: String) -> Self {
+        Diagnostic { file: None, line, col, len, message, hint: None, notes: Vec::new() }
+    }
+
+    pub fn with_hint(mut self, hint: String) -> Self {
+        self.hint = Some(hint);
+        self
+    }
+
+    /// The diagnostic the way a compiler prints it, `file:line:col`, the
+    /// source line with carets under the token, the hint and the notes.
+    ///
+    /// ```text
+    /// loop.asm:2:12: error: undefined label LOP
+    ///   2 |         BRp LOP
+    ///     |             ^^^
+    ///     = hint: did you mean LOOP?
+    /// ```
+    ///
+    /// `file` and `source` are the name and text of the source that was
+    /// assembled, a problem in an included file is shown from that file.
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let included = self.file.as_ref().map(|path| (path.as_str(), fs::read_to_string(path).unwrap_or_default()));
+        let (file, source) = match &included {
+            Some((path, text)) => (*path, text.as_str()),
+            None => (file, source),
+        };
+        let mut out = format!("{}:{}: error: {}\n", file, self, self.message);
+        if let Some(text) = source.lines().nth(self.line.wrapping_sub(1)) {
+            let number = self.line.to_string();
+            let gutter = " ".repeat(number.len());
+            // keep the tabs of the line so the carets land under the token
+            let indent: String = text.chars()
+                .take(self.col.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            out += &format!("  {} | {}\n", number, text);
+            out += &format!("  {} | {}{}\n", gutter, indent, "^".repeat(self.len.max(1)));
+            if let Some(hint) = &self.hint {
+                out += &format!("  {} = hint: {}\n", gutter, hint);
+            }
+            for note in &self.notes {
+                out += &format!("  {} = note: {}\n", gutter, note);
+            }
+        } else {
+            if let Some(hint) = &self.hint {
+                out += &format!("  = hint: {}\n", hint);
+            }
+            for note in &self.notes {
+                out += &format!("  = note: {}\n", note);
+            }
+        }
+        out
+    }
+}
+
+/// `line:col`, render gives the full report.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// The candidate closest to `name`, if one is close enough to be a typo.
+pub fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let upper = name.to_uppercase();
+    candidates
+        .map(|candidate| (distance(&upper, &candidate.to_uppercase()), candidate))
+        .filter(|(d, _)| *d <= 2.min(name.len() / 2))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Edit distance between two strings.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + (ca != *cb) as usize;
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render(){
+        let source = ".ORIG x3000\n\tBRp LOP\n.END";
+        let diagnostic = Diagnostic::new(2, 6, 3, String::from("undefined label LOP"))
+            .with_hint(String::from("did you mean LOOP?"));
+        assert_eq!(diagnostic.render("loop.asm", source),
+            "loop.asm:2:6: error: undefined label LOP\n  2 | \tBRp LOP\n    | \t    ^^^\n    = hint: did you mean LOOP?\n");
+    }
+
+    #[test]
+    fn test_suggest(){
+        let labels = ["LOOP", "DONE", "COUNT"];
+        assert_eq!(suggest("LOP", labels.iter().copied()), Some("LOOP"));
+        assert_eq!(suggest("loop", labels.iter().copied()), Some("LOOP"));
+        assert_eq!(suggest("START", labels.iter().copied()), None);
+    }
+}
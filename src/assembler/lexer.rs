@@ -0,0 +1,157 @@
+//! Splitting source lines into tokens.
+//!
+//! Numbers follow lc3as: `#10` and `10` are decimal, `x1F` is hex (a word
+//! made of an x followed by hex digits is always a number, never a label).
+//! Operands may be separated by commas, spaces or both and everything after
+//! a `;` is a comment.
+
+/// What a token is.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TokenKind {
+    /// opcode, directive, register or label, as written.
+    Word(String),
+    /// a numeric literal.
+    Number(i32),
+    /// a string literal, escapes resolved.
+    Str(Vec<u8>),
+}
+
+/// A token and where it starts in its line (columns count from 1).
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub col: usize,
+    pub len: usize,
+}
+
+/// A lexing error, the message and the column it refers to.
+#[derive(Debug, PartialEq)]
+pub struct LexError {
+    pub col: usize,
+    pub message: String,
+}
+
+/// The tokens of one source line.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, LexError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() || c == ',' {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let kind = if c == '"' {
+            let (bytes, end) = string(&chars, i)?;
+            i = end;
+            TokenKind::Str(bytes)
+        } else {
+            while i < chars.len() && !chars[i].is_whitespace() && chars[i] != ',' && chars[i] != ';' && chars[i] != '"' {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            match number(&text) {
+                Some(Ok(n)) => TokenKind::Number(n),
+                Some(Err(())) => return Err(LexError { col: start + 1, message: format!("invalid number {}", text) }),
+                None => TokenKind::Word(text),
+            }
+        };
+        tokens.push(Token { kind, col: start + 1, len: i - start });
+    }
+    Ok(tokens)
+}
+
+/// Parse `text` as a number, None if it isn't meant to be one and
+/// Some(Err) if it looks like one but isn't valid (`#1x`, `12a`).
+fn number(text: &str) -> Option<Result<i32, ()>> {
+    let (digits, radix) = if let Some(rest) = text.strip_prefix('#') {
+        (rest, 10)
+    } else if let Some(rest) = text.strip_prefix('x').or_else(|| text.strip_prefix('X')) {
+        let hex = rest.strip_prefix('-').unwrap_or(rest);
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;                                                // a label like xor_mask
+        }
+        (rest, 16)
+    } else if text.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        (text, 10)
+    } else {
+        return None;
+    };
+    Some(i32::from_str_radix(digits, radix).map_err(|_| ()))
+}
+
+/// Read the string literal starting at `chars[start]` (the opening quote),
+/// returns its bytes and the index past the closing quote.
+fn string(chars: &[char], start: usize) -> Result<(Vec<u8>, usize), LexError> {
+    let mut bytes = Vec::new();
+    let mut i = start + 1;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '"' {
+            return Ok((bytes, i + 1));
+        }
+        if c == '\\' {
+            i += 1;
+            let escaped = match chars.get(i) {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some('e') => 0x1B,
+                Some('0') => 0,
+                Some('\\') => b'\\',
+                Some('"') => b'"',
+                _ => return Err(LexError { col: i, message: String::from("unknown escape sequence") }),
+            };
+            bytes.push(escaped);
+        } else if c.is_ascii() {
+            bytes.push(c as u8);
+        } else {
+            return Err(LexError { col: i + 1, message: format!("non-ASCII character '{}' in string", c) });
+        }
+        i += 1;
+    }
+    Err(LexError { col: start + 1, message: String::from("unterminated string") })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<TokenKind> {
+        tokenize(line).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn test_tokenize(){
+        assert_eq!(kinds("LOOP ADD R1,R1, #-1 ; count down"), vec![
+            TokenKind::Word(String::from("LOOP")),
+            TokenKind::Word(String::from("ADD")),
+            TokenKind::Word(String::from("R1")),
+            TokenKind::Word(String::from("R1")),
+            TokenKind::Number(-1),
+        ]);
+        assert_eq!(kinds(r#"MSG .STRINGZ "a;\"b\n""#)[2], TokenKind::Str(b"a;\"b\n".to_vec()));
+        let tokens = tokenize("  .FILL x3000").unwrap();
+        assert_eq!((tokens[1].col, tokens[1].len), (9, 5));
+        assert!(tokenize("\"open").is_err());
+    }
+
+    #[test]
+    fn test_number(){
+        assert_eq!(number("#10"), Some(Ok(10)));
+        assert_eq!(number("-3"), Some(Ok(-3)));
+        assert_eq!(number("xFFFF"), Some(Ok(0xFFFF)));
+        assert_eq!(number("x-10"), Some(Ok(-16)));
+        assert_eq!(number("xor"), None);
+        assert_eq!(number("LOOP"), None);
+        assert_eq!(number("12a"), Some(Err(())));
+        assert_eq!(number("#99999"), Some(Ok(99999)), "in range of i32, the assembler checks the field width");
+        assert_eq!(number("#1x"), Some(Err(())));
+    }
+}
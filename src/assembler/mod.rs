@@ -0,0 +1,819 @@
+//! LC3 assembler, reads the lc3as dialect and produces object images.
+//!
+//! A source file is a single `.ORIG` block ended by `.END`: instructions,
+//! the trap aliases (GETC, OUT, PUTS, IN, PUTSP, HALT) and the `.FILL`,
+//! `.BLKW` and `.STRINGZ` directives, each optionally preceded by a label.
+//! Like lc3as a numeric PC offset operand is the offset itself while a label
+//! operand is turned into the offset to that label.
+//!
+//! `.IMPORT LABEL` lets a program refer to a label of another module and
+//! `.EXPORT LABEL` offers one of its own, such a program is assembled to an
+//! [`Object`] that the [`linker`](crate::linker) combines with others.
+//!
+//! Before that the [`preprocessor`] resolves `.INCLUDE`, `.DEFINE`,
+//! `.MACRO` and `.IF`, so a program can share code kept in other files.
+//!
+//! ```
+//! use virtual_machine::assembler::assemble;
+//!
+//! let program = assemble(".ORIG x3000\nLOOP BRnzp LOOP\n.END\n").unwrap();
+//! assert_eq!(program.words, vec![0x0FFF]);
+//! ```
+
+pub mod diagnostic;
+pub mod lexer;
+pub mod preprocessor;
+
+pub use diagnostic::Diagnostic;
+
+use crate::loader::Image;
+use crate::object::{Object, Relocation, RelocationKind};
+use crate::symbols::SymbolTable;
+use diagnostic::suggest;
+use lexer::{Token, TokenKind};
+use preprocessor::{preprocess, Line, DIRECTIVES};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Trap aliases and their vectors.
+const TRAP_ALIASES: [(&str, u16); 6] = [
+    ("GETC", 0x20), ("OUT", 0x21), ("PUTS", 0x22), ("IN", 0x23), ("PUTSP", 0x24), ("HALT", 0x25),
+];
+
+/// Instructions and directives, branches (BR, BRnz, ...) are recognised separately.
+const MNEMONICS: [&str; 23] = [
+    "ADD", "AND", "NOT", "LD", "LDI", "LDR", "LEA", "ST", "STI", "STR", "JMP", "RET",
+    "JSR", "JSRR", "TRAP", "RTI", ".ORIG", ".FILL", ".BLKW", ".STRINGZ", ".END",
+    ".IMPORT", ".EXPORT",
+];
+
+/// An instruction field holding a signed number, named the way the ISA does.
+#[derive(Clone, Copy)]
+struct Field {
+    name: &'static str,
+    bits: u32,
+}
+
+const IMM5: Field = Field { name: "imm5", bits: 5 };
+const OFFSET6: Field = Field { name: "offset6", bits: 6 };
+const PCOFFSET9: Field = Field { name: "PCoffset9", bits: 9 };
+const PCOFFSET11: Field = Field { name: "PCoffset11", bits: 11 };
+
+impl Field {
+    fn min(self) -> i32 {
+        -(1 << (self.bits - 1))
+    }
+
+    fn max(self) -> i32 {
+        (1 << (self.bits - 1)) - 1
+    }
+
+    fn mask(self, n: i32) -> u16 {
+        n as u16 & ((1 << self.bits) - 1)
+    }
+}
+
+/// An assembled program, the words placed at `origin` and the address of
+/// every label in the order they were defined.
+///
+/// A program that imports labels can only be run once linked, the words
+/// referring to them are left for the linker along with the words that
+/// depend on where the program is placed.
+#[derive(Debug, PartialEq)]
+pub struct Assembly {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    pub symbols: Vec<(String, u16)>,
+    pub exports: Vec<String>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Assembly {
+    /// The object image of the program, ready for the loader.
+    pub fn image(&self) -> Image {
+        Image { origin: self.origin, data: self.words.clone() }
+    }
+
+    /// The program as a relocatable module, ready for the linker.
+    pub fn object(&self) -> Object {
+        Object {
+            origin: self.origin,
+            words: self.words.clone(),
+            symbols: self.symbols.clone(),
+            exports: self.exports.clone(),
+            imports: self.imports.clone(),
+            relocations: self.relocations.clone(),
+        }
+    }
+
+    /// The labels of the program, `to_sym` gives the lc3as symbol file.
+    pub fn symbol_table(&self) -> SymbolTable {
+        SymbolTable::from_pairs(&self.symbols)
+    }
+}
+
+/// Where the program goes, worked out before encoding it.
+struct Layout {
+    origin: u16,
+    symbols: Vec<(String, u16)>,
+    /// address of every statement up to `end`.
+    addresses: Vec<u16>,
+    /// index of the statement ending the program (.END or past the last one).
+    end: usize,
+    exports: Vec<String>,
+    imports: Vec<String>,
+}
+
+/// What the labels of a program stand for while it's encoded.
+struct Labels<'a> {
+    addresses: HashMap<&'a str, u16>,
+    /// labels left for the linker.
+    imports: &'a [String],
+}
+
+/// One source line that holds something, a label, an operation or both.
+struct Statement {
+    line: usize,
+    label: Option<Token>,
+    mnemonic: Option<(String, Token)>,                                  // upper cased, as written
+    operands: Vec<Token>,
+}
+
+/// Assemble the source of a program, included files are looked up in the
+/// current directory.
+///
+/// Assembly goes on past errors so every problem is reported at once, the
+/// diagnostics come back sorted by position.
+pub fn assemble(source: &str) -> Result<Assembly, Vec<Diagnostic>> {
+    assemble_in(source, Path::new(""))
+}
+
+/// Assemble the source of a program that includes files from `dir`,
+/// usually the directory the source was read from.
+pub fn assemble_in(source: &str, dir: &Path) -> Result<Assembly, Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let lines = preprocess(source, dir, &mut diagnostics);
+    // past the preprocessor a diagnostic's line is its index in lines (from 1)
+    let mut errors = Vec::new();
+    let statements = parse(&lines, &mut errors);
+    let mut program = Assembly {
+        origin: 0,
+        words: Vec::new(),
+        symbols: Vec::new(),
+        exports: Vec::new(),
+        imports: Vec::new(),
+        relocations: Vec::new(),
+    };
+    if let Some(layout) = layout(&statements, &lines, &mut errors) {
+        let labels = Labels {
+            addresses: layout.symbols.iter().map(|(name, address)| (name.as_str(), *address)).collect(),
+            imports: &layout.imports,
+        };
+        for (statement, pc) in statements[1..layout.end].iter().zip(&layout.addresses[1..]) {
+            if let Err(diagnostic) = encode(statement, *pc, &labels, &mut program.words, &mut program.relocations) {
+                errors.push(diagnostic);
+            }
+        }
+        program.origin = layout.origin;
+        program.symbols = layout.symbols;
+        program.exports = layout.exports;
+        program.imports = layout.imports;
+    }
+    diagnostics.extend(errors.into_iter().map(|d| match lines.get(d.line.wrapping_sub(1)) {
+        Some(line) => line.location.place(d),
+        None => d,
+    }));
+    if diagnostics.is_empty() {
+        return Ok(program);
+    }
+    // sizing .BLKW and .STRINGZ and encoding them find the same problems
+    diagnostics.sort_by(|a, b| (&a.file, a.line, a.col).cmp(&(&b.file, b.line, b.col)));
+    diagnostics.dedup();
+    Err(diagnostics)
+}
+
+/// Split every line in a label, a mnemonic and its operands, lines that
+/// don't make sense are reported and left out.
+fn parse(lines: &[Line], diagnostics: &mut Vec<Diagnostic>) -> Vec<Statement> {
+    let mut statements = Vec::new();
+    for (i, source) in lines.iter().enumerate() {
+        let line = i + 1;
+        match parse_line(line, &source.tokens) {
+            Ok(Some(statement)) => {
+                let end = matches!(&statement.mnemonic, Some((name, _)) if name == ".END");
+                statements.push(statement);
+                if end {
+                    break;                                              // the rest of the file is ignored
+                }
+            }
+            Ok(None) => {}
+            Err(diagnostic) => diagnostics.push(diagnostic),
+        }
+    }
+    statements
+}
+
+fn parse_line(line: usize, tokens: &[Token]) -> Result<Option<Statement>, Diagnostic> {
+    let mut tokens = tokens.iter().cloned();
+    let first = match tokens.next() {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let mut statement = Statement { line, label: None, mnemonic: None, operands: Vec::new() };
+    match mnemonic(&first) {
+        Some(name) => statement.mnemonic = Some((name, first)),
+        None => {
+            let second = tokens.next();
+            match second.as_ref().and_then(mnemonic) {
+                Some(name) => statement.mnemonic = Some((name, second.unwrap())),
+                None if second.is_some() => return Err(unknown_instruction(line, &first, &second.unwrap())),
+                None => {}
+            }
+            check_label(line, &first)?;
+            statement.label = Some(first);
+        }
+    }
+    statement.operands = tokens.collect();
+    Ok(Some(statement))
+}
+
+/// Neither of the first two tokens is an instruction: either the first one is
+/// a misspelled instruction or it's a label followed by a misspelled one.
+fn unknown_instruction(line: usize, first: &Token, second: &Token) -> Diagnostic {
+    let known = || MNEMONICS.iter().chain(TRAP_ALIASES.iter().map(|(alias, _)| alias)).copied();
+    if let TokenKind::Word(word) = &first.kind {
+        if let Some(instruction) = suggest(word, known()) {
+            return error(line, first, format!("unknown instruction {}", word))
+                .with_hint(format!("did you mean {}?", instruction));
+        }
+    }
+    let diagnostic = error(line, second, format!("expected an instruction or directive, found {}", text_of(second)));
+    match &second.kind {
+        TokenKind::Word(word) => match suggest(word, known()) {
+            Some(instruction) => diagnostic.with_hint(format!("did you mean {}?", instruction)),
+            None => diagnostic,
+        },
+        _ => diagnostic,
+    }
+}
+
+/// The upper cased mnemonic `token` spells, if it's one.
+fn mnemonic(token: &Token) -> Option<String> {
+    let word = match &token.kind {
+        TokenKind::Word(word) => word.to_uppercase(),
+        _ => return None,
+    };
+    let known = MNEMONICS.contains(&word.as_str())
+        || TRAP_ALIASES.iter().any(|(alias, _)| *alias == word)
+        || branch_flags(&word).is_some()
+        || word.starts_with('.');                                       // unknown directives are reported by encode
+    if known { Some(word) } else { None }
+}
+
+/// The n, z and p bits of a branch mnemonic, BR alone branches always.
+fn branch_flags(word: &str) -> Option<u16> {
+    let flags = word.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(0b111);
+    }
+    let mut bits = 0;
+    let mut rest = flags;
+    for (letter, bit) in [('N', 0b100), ('Z', 0b010), ('P', 0b001)] {
+        if let Some(r) = rest.strip_prefix(letter) {
+            bits |= bit;
+            rest = r;
+        }
+    }
+    if rest.is_empty() { Some(bits) } else { None }
+}
+
+/// Labels start with a letter or underscore and go on with letters, digits
+/// and underscores, register names are taken.
+fn check_label(line: usize, token: &Token) -> Result<(), Diagnostic> {
+    let name = match &token.kind {
+        TokenKind::Word(word) => word,
+        _ => return Err(error(line, token, format!("expected a label or instruction, found {}", text_of(token)))),
+    };
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(error(line, token, format!("invalid label {}", name))
+            .with_hint(String::from("labels start with a letter or _ followed by letters, digits or _")));
+    }
+    if register(token).is_some() {
+        return Err(error(line, token, format!("register {} can't be used as a label", name)));
+    }
+    Ok(())
+}
+
+/// Find the origin, the address of every label and where the program ends.
+/// None if there's no .ORIG to start from.
+fn layout(statements: &[Statement], lines: &[Line], diagnostics: &mut Vec<Diagnostic>) -> Option<Layout> {
+    let first = match statements.first() {
+        Some(statement) => statement,
+        None => {
+            diagnostics.push(Diagnostic::new(1, 1, 0, String::from("missing .ORIG"))
+                .with_hint(String::from("start the program with .ORIG and its address, e.g. .ORIG x3000")));
+            return None;
+        }
+    };
+    let origin = match &first.mnemonic {
+        Some((name, token)) if name == ".ORIG" => {
+            if let Some(label) = &first.label {
+                diagnostics.push(error(first.line, label, String::from("a label can't be placed on .ORIG"))
+                    .with_hint(String::from("put the label on the line after .ORIG")));
+            }
+            let address = expect_operands(first, token, 1)
+                .and_then(|_| number(first.line, &first.operands[0], 0, 0xFFFF));
+            match address {
+                Ok(address) => address as u16,
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic);
+                    return None;
+                }
+            }
+        }
+        _ => {
+            let token = first.label.as_ref().or(first.mnemonic.as_ref().map(|(_, t)| t)).unwrap();
+            diagnostics.push(error(first.line, token, String::from("expected .ORIG before the first statement"))
+                .with_hint(String::from("start the program with .ORIG and its address, e.g. .ORIG x3000")));
+            return None;
+        }
+    };
+    let mut layout = Layout {
+        origin,
+        symbols: Vec::new(),
+        addresses: vec![origin],
+        end: statements.len(),
+        exports: Vec::new(),
+        imports: Vec::new(),
+    };
+    let mut defined: HashMap<String, usize> = HashMap::new();           // label -> line
+    let mut linked: Vec<(&Statement, &Token)> = Vec::new();             // .IMPORT and .EXPORT operands
+    let mut pc = origin as usize;
+    for (i, statement) in statements.iter().enumerate().skip(1) {
+        layout.addresses.push(pc as u16);
+        if let Some(label) = &statement.label {
+            let name = text_of(label);
+            match defined.get(&name) {
+                Some(line) => diagnostics.push(error(statement.line, label, format!("label {} is defined twice", name))
+                    .with_hint(format!("first defined on {}", lines[line - 1].location))),
+                None => {
+                    defined.insert(name.clone(), statement.line);
+                    layout.symbols.push((name, pc as u16));
+                }
+            }
+        }
+        let size = match &statement.mnemonic {
+            Some((name, token)) => match name.as_str() {
+                ".END" => {
+                    layout.end = i;
+                    break;
+                }
+                ".ORIG" => {
+                    diagnostics.push(error(statement.line, token, String::from("only one .ORIG block per file"))
+                        .with_hint(String::from("assemble each block from its own file")));
+                    Ok(0)
+                }
+                ".BLKW" => expect_operands(statement, token, 1)
+                    .and_then(|_| number(statement.line, &statement.operands[0], 0, 0x10000))
+                    .map(|count| count as usize),
+                ".STRINGZ" => expect_operands(statement, token, 1)
+                    .and_then(|_| string(statement.line, &statement.operands[0]))
+                    .map(|bytes| bytes.len() + 1),
+                ".IMPORT" | ".EXPORT" => {
+                    let operand = expect_operands(statement, token, 1)
+                        .and_then(|_| check_label(statement.line, &statement.operands[0]));
+                    if let Some(label) = &statement.label {
+                        diagnostics.push(error(statement.line, label, format!("a label can't be placed on {}", text_of(token))));
+                    }
+                    operand.map(|_| {
+                        linked.push((statement, &statement.operands[0]));
+                        0
+                    })
+                }
+                _ => Ok(1),
+            }
+            .unwrap_or_else(|diagnostic| {
+                diagnostics.push(diagnostic);
+                0
+            }),
+            None => 0,
+        };
+        pc += size;
+        if pc > 0x10000 {
+            let token = statement.mnemonic.as_ref().map(|(_, t)| t).unwrap();
+            diagnostics.push(error(statement.line, token, String::from("program runs past the end of memory")));
+            layout.end = i + 1;
+            break;
+        }
+    }
+    for (statement, token) in linked {
+        let name = text_of(token);
+        let imported = matches!(&statement.mnemonic, Some((directive, _)) if directive == ".IMPORT");
+        let list = if imported { &mut layout.imports } else { &mut layout.exports };
+        if list.contains(&name) {
+            continue;
+        }
+        match defined.get(&name) {
+            Some(line) if imported => diagnostics.push(error(statement.line, token, format!("{} is imported but defined here too", name))
+                .with_hint(format!("defined on {}, drop the .IMPORT or rename the label", lines[line - 1].location))),
+            None if !imported => diagnostics.push(error(statement.line, token, format!("exported label {} is not defined", name))),
+            _ => list.push(name),
+        }
+    }
+    Some(layout)
+}
+
+/// Append the words of `statement`, placed at `pc`, to `words`.
+///
+/// A word referring to an imported label, or a .FILL of a label, comes with
+/// a relocation telling the linker how to patch it.
+fn encode(statement: &Statement, pc: u16, labels: &Labels, words: &mut Vec<u16>, relocations: &mut Vec<Relocation>)
+    -> Result<(), Diagnostic> {
+    let (name, token) = match &statement.mnemonic {
+        Some(mnemonic) => mnemonic,
+        None => return Ok(()),
+    };
+    let mut relocation = None;
+    let line = statement.line;
+    let ops = &statement.operands;
+    let reg = |i: usize| expect_register(line, &ops[i]);
+    let word = match name.as_str() {
+        "ADD" | "AND" => {
+            expect_operands(statement, token, 3)?;
+            let base = if name == "ADD" { 0x1000 } else { 0x5000 };
+            let operand = match register(&ops[2]) {
+                Some(r) => r,
+                None => 0x20 | immediate(line, &ops[2], IMM5)?,
+            };
+            base | reg(0)? << 9 | reg(1)? << 6 | operand
+        }
+        "NOT" => {
+            expect_operands(statement, token, 2)?;
+            0x9000 | reg(0)? << 9 | reg(1)? << 6 | 0x3F
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect_operands(statement, token, 2)?;
+            let base = match name.as_str() {
+                "LD" => 0x2000,
+                "LDI" => 0xA000,
+                "LEA" => 0xE000,
+                "ST" => 0x3000,
+                _ => 0xB000,
+            };
+            base | reg(0)? << 9 | offset(line, &ops[1], pc, PCOFFSET9, labels, &mut relocation)?
+        }
+        "LDR" | "STR" => {
+            expect_operands(statement, token, 3)?;
+            let base = if name == "LDR" { 0x6000 } else { 0x7000 };
+            base | reg(0)? << 9 | reg(1)? << 6 | immediate(line, &ops[2], OFFSET6)?
+        }
+        "JMP" | "JSRR" => {
+            expect_operands(statement, token, 1)?;
+            let base = if name == "JMP" { 0xC000 } else { 0x4000 };
+            base | reg(0)? << 6
+        }
+        "RET" => {
+            expect_operands(statement, token, 0)?;
+            0xC1C0
+        }
+        "JSR" => {
+            expect_operands(statement, token, 1)?;
+            0x4800 | offset(line, &ops[0], pc, PCOFFSET11, labels, &mut relocation)?
+        }
+        "TRAP" => {
+            expect_operands(statement, token, 1)?;
+            match ops[0].kind {
+                TokenKind::Number(n) if !(0..=0xFF).contains(&n) => {
+                    return Err(error(line, &ops[0], format!("trap vector #{} does not fit in trapvect8", n))
+                        .with_hint(String::from("trap vectors go from x00 to xFF")));
+                }
+                _ => 0xF000 | number(line, &ops[0], 0, 0xFF)? as u16,
+            }
+        }
+        "RTI" => {
+            expect_operands(statement, token, 0)?;
+            0x8000
+        }
+        ".FILL" => {
+            expect_operands(statement, token, 1)?;
+            match &ops[0].kind {
+                TokenKind::Word(label) if labels.imports.contains(label) => {
+                    relocation = Some(Relocation { offset: 0, kind: RelocationKind::Fill, symbol: Some(label.clone()) });
+                    0
+                }
+                TokenKind::Word(label) => {
+                    relocation = Some(Relocation { offset: 0, kind: RelocationKind::Fill, symbol: None });
+                    lookup(line, &ops[0], label, &labels.addresses)?
+                }
+                _ => number(line, &ops[0], -0x8000, 0xFFFF)? as u16,
+            }
+        }
+        ".BLKW" => {
+            expect_operands(statement, token, 1)?;
+            let count = number(line, &ops[0], 0, 0x10000)? as usize;
+            words.resize(words.len() + count, 0);
+            return Ok(());
+        }
+        ".STRINGZ" => {
+            expect_operands(statement, token, 1)?;
+            words.extend(string(line, &ops[0])?.iter().map(|&b| b as u16));
+            words.push(0);
+            return Ok(());
+        }
+        ".IMPORT" | ".EXPORT" => return Ok(()),                        // done by layout
+        _ => {
+            if let Some(flags) = branch_flags(name) {
+                expect_operands(statement, token, 1)?;
+                flags << 9 | offset(line, &ops[0], pc, PCOFFSET9, labels, &mut relocation)?
+            } else if let Some((_, vector)) = TRAP_ALIASES.iter().find(|(alias, _)| alias == name) {
+                expect_operands(statement, token, 0)?;
+                0xF000 | vector
+            } else {
+                let diagnostic = error(line, token, format!("unknown directive {}", text_of(token)));
+                let directives = MNEMONICS.iter().chain(&DIRECTIVES).copied().filter(|m| m.starts_with('.'));
+                return Err(match suggest(name, directives) {
+                    Some(directive) => diagnostic.with_hint(format!("did you mean {}?", directive)),
+                    None => diagnostic.with_hint(String::from("directives are .ORIG, .FILL, .BLKW, .STRINGZ, .END, .IMPORT and .EXPORT")),
+                });
+            }
+        }
+    };
+    if let Some(relocation) = relocation {
+        relocations.push(Relocation { offset: words.len() as u16, ..relocation });
+    }
+    words.push(word);
+    Ok(())
+}
+
+fn error(line: usize, token: &Token, message: String) -> Diagnostic {
+    Diagnostic::new(line, token.col, token.len, message)
+}
+
+/// The token as it was written.
+fn text_of(token: &Token) -> String {
+    match &token.kind {
+        TokenKind::Word(word) => word.clone(),
+        TokenKind::Number(n) => format!("#{}", n),
+        TokenKind::Str(bytes) => format!("{:?}", String::from_utf8_lossy(bytes)),
+    }
+}
+
+/// How an instruction or directive is written.
+fn syntax(name: &str) -> String {
+    match name {
+        "ADD" | "AND" => format!("{0} DR, SR1, SR2 or {0} DR, SR1, #imm5", name),
+        "NOT" => String::from("NOT DR, SR"),
+        "LD" | "LDI" | "LEA" => format!("{} DR, LABEL", name),
+        "ST" | "STI" => format!("{} SR, LABEL", name),
+        "LDR" => String::from("LDR DR, BaseR, #offset6"),
+        "STR" => String::from("STR SR, BaseR, #offset6"),
+        "JMP" | "JSRR" => format!("{} BaseR", name),
+        "JSR" => String::from("JSR LABEL"),
+        "TRAP" => String::from("TRAP x25"),
+        ".ORIG" => String::from(".ORIG x3000"),
+        ".FILL" => String::from(".FILL x1234 or .FILL LABEL"),
+        ".BLKW" => String::from(".BLKW 10"),
+        ".STRINGZ" => String::from(".STRINGZ \"text\""),
+        ".IMPORT" | ".EXPORT" => format!("{} LABEL", name),
+        _ if branch_flags(name).is_some() => format!("{} LABEL", name),
+        _ => name.to_string(),
+    }
+}
+
+fn expect_operands(statement: &Statement, mnemonic: &Token, count: usize) -> Result<(), Diagnostic> {
+    let found = statement.operands.len();
+    if found == count {
+        return Ok(());
+    }
+    let (col, len) = match statement.operands.get(count) {
+        Some(extra) => {
+            let last = statement.operands.last().unwrap();
+            (extra.col, last.col + last.len - extra.col)                // every extra operand
+        }
+        None => {
+            let last = statement.operands.last().unwrap_or(mnemonic);
+            (last.col + last.len, 1)                                    // where the next one should be
+        }
+    };
+    let name = text_of(mnemonic);
+    let takes = match count {
+        0 => String::from("no operands"),
+        1 => String::from("1 operand"),
+        n => format!("{} operands", n),
+    };
+    Err(Diagnostic::new(statement.line, col, len, format!("{} takes {}, found {}", name, takes, found))
+        .with_hint(format!("write it as {}", syntax(&name.to_uppercase()))))
+}
+
+/// The number of the register `token` names (R0-R7, any case).
+fn register(token: &Token) -> Option<u16> {
+    match &token.kind {
+        TokenKind::Word(word) if word.len() == 2 && word.to_uppercase().starts_with('R') => {
+            word[1..].parse().ok().filter(|r| *r < 8)
+        }
+        _ => None,
+    }
+}
+
+fn expect_register(line: usize, token: &Token) -> Result<u16, Diagnostic> {
+    register(token).ok_or_else(|| error(line, token, format!("expected a register, found {}", text_of(token)))
+        .with_hint(String::from("registers are R0 to R7")))
+}
+
+/// A numeric operand within `min..=max`.
+fn number(line: usize, token: &Token, min: i32, max: i32) -> Result<i32, Diagnostic> {
+    match token.kind {
+        TokenKind::Number(n) if (min..=max).contains(&n) => Ok(n),
+        TokenKind::Number(n) => Err(error(line, token, format!("#{} is out of range {}..{}", n, min, max))),
+        _ => Err(error(line, token, format!("expected a number, found {}", text_of(token)))),
+    }
+}
+
+/// A number that has to fit in `field`, masked to it.
+fn immediate(line: usize, token: &Token, field: Field) -> Result<u16, Diagnostic> {
+    match token.kind {
+        TokenKind::Number(n) if (field.min()..=field.max()).contains(&n) => Ok(field.mask(n)),
+        TokenKind::Number(n) => {
+            let what = if field.name == "imm5" { "immediate" } else { "offset" };
+            Err(error(line, token, format!("{} #{} does not fit in {}", what, n, field.name))
+                .with_hint(format!("{} holds {}..{}", field.name, field.min(), field.max())))
+        }
+        _ => Err(error(line, token, format!("expected a number, found {}", text_of(token)))),
+    }
+}
+
+/// A PC offset, either given as a number or the distance from the
+/// incremented PC to a label. The offset to an imported label is left
+/// at 0 for the linker, `relocation` says where it goes.
+fn offset(line: usize, token: &Token, pc: u16, field: Field, labels: &Labels, relocation: &mut Option<Relocation>)
+    -> Result<u16, Diagnostic> {
+    let label = match &token.kind {
+        TokenKind::Word(label) => label,
+        _ => return immediate(line, token, field),
+    };
+    if labels.imports.contains(label) {
+        let kind = if field.bits == PCOFFSET9.bits { RelocationKind::PcOffset9 } else { RelocationKind::PcOffset11 };
+        *relocation = Some(Relocation { offset: 0, kind, symbol: Some(label.clone()) });
+        return Ok(0);
+    }
+    let target = lookup(line, token, label, &labels.addresses)?;
+    let distance = target as i32 - (pc as i32 + 1);
+    let over = if distance > field.max() { distance - field.max() } else { field.min() - distance };
+    if over > 0 {
+        let words = if over == 1 { "word" } else { "words" };
+        return Err(error(line, token, format!("{} out of range by {} {}", field.name, over, words))
+            .with_hint(format!("{} is at x{:04X}, {} words from the next instruction but {} reaches {}..{}; \
+                move it closer or reach it through a pointer with LDI or LDR",
+                label, target, distance, field.name, field.min(), field.max())));
+    }
+    Ok(field.mask(distance))
+}
+
+fn lookup(line: usize, token: &Token, label: &str, symbols: &HashMap<&str, u16>) -> Result<u16, Diagnostic> {
+    if let Some(address) = symbols.get(label) {
+        return Ok(*address);
+    }
+    let diagnostic = error(line, token, format!("undefined label {}", label));
+    Err(match suggest(label, symbols.keys().copied()) {
+        Some(known) if known.eq_ignore_ascii_case(label) =>
+            diagnostic.with_hint(format!("labels are case sensitive, did you mean {}?", known)),
+        Some(known) => diagnostic.with_hint(format!("did you mean {}?", known)),
+        None => diagnostic,
+    })
+}
+
+fn string(line: usize, token: &Token) -> Result<&[u8], Diagnostic> {
+    match &token.kind {
+        TokenKind::Str(bytes) => Ok(bytes),
+        _ => Err(error(line, token, format!("expected a string, found {}", text_of(token)))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::LC3OS;
+
+    #[test]
+    fn test_assemble_lc3os(){
+        let source = std::fs::read_to_string("lc3os.asm").unwrap();
+        let os = assemble(&source).unwrap();
+        assert_eq!(os.image().to_bytes(), LC3OS, "same bytes as the bundled image");
+        assert!(os.symbols.contains(&(String::from("TRAP_HALT"), 0x0241)));
+        assert_eq!(os.symbol_table(), SymbolTable::lc3os(), "same symbols as the bundled table");
+    }
+
+    #[test]
+    fn test_encoding(){
+        let source = "
+            .ORIG x3000
+    START   ADD R1, R2, R3
+            add r1, r2, #-16
+            AND R0, R0, #0
+            NOT R4, R5
+            LD R0, DATA
+            LDI R1, DATA
+            LDR R2, R6, #-1
+            LEA R3, START
+            ST R0, DATA
+            STI R1, DATA
+            STR R2, R6, #31
+            BRnp START
+            BR #0
+            JMP R2
+            RET
+            JSR START
+            JSRR R3
+            RTI
+            TRAP x25
+            GETC
+            HALT
+    DATA    .FILL xBEEF
+            .FILL START
+            .BLKW 2
+            .STRINGZ \"hi\"
+            .END
+            this is ignored";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.origin, 0x3000);
+        assert_eq!(program.words, vec![
+            0x1283, 0x12B0, 0x5020, 0x997F,
+            0x2010, 0xA20F, 0x65BF, 0xE7F8,
+            0x300C, 0xB20B, 0x759F, 0x0BF4,
+            0x0E00, 0xC080, 0xC1C0, 0x4FF0,
+            0x40C0, 0x8000, 0xF025, 0xF020,
+            0xF025, 0xBEEF, 0x3000, 0, 0,
+            b'h' as u16, b'i' as u16, 0,
+        ]);
+        assert_eq!(program.symbols, vec![(String::from("START"), 0x3000), (String::from("DATA"), 0x3015)]);
+    }
+
+    #[test]
+    fn test_errors(){
+        let error = |source: &str| assemble(source).unwrap_err().remove(0);
+        assert_eq!(error("ADD R0, R0, #1").message, "expected .ORIG before the first statement");
+        assert_eq!(error(".ORIG x3000\nBR LOOP\n.END"), Diagnostic::new(2, 4, 4, String::from("undefined label LOOP")));
+        let diagnostic = error(".ORIG x3000\nADD R0, R0, #20");
+        assert_eq!(diagnostic.message, "immediate #20 does not fit in imm5");
+        assert_eq!(diagnostic.hint.as_deref(), Some("imm5 holds -16..15"));
+        assert_eq!(error(".ORIG x3000\nLDR R0, R6, #40").message, "offset #40 does not fit in offset6");
+        let diagnostic = error(".ORIG x3000\nADD R0, R0");
+        assert_eq!((diagnostic.col, diagnostic.message.as_str()), (11, "ADD takes 3 operands, found 2"));
+        assert_eq!(diagnostic.hint.as_deref(), Some("write it as ADD DR, SR1, SR2 or ADD DR, SR1, #imm5"));
+        let diagnostic = error(".ORIG x3000\nX .FILL 0\nX .FILL 1");
+        assert_eq!((diagnostic.line, diagnostic.hint.as_deref()), (3, Some("first defined on line 2")));
+        assert_eq!(error(".ORIG x3000\nLD R0, FAR\n.BLKW 300\nFAR .FILL 0").message, "PCoffset9 out of range by 45 words");
+        assert_eq!(error(".ORIG x3000\nJSR #1024").message, "offset #1024 does not fit in PCoffset11");
+        assert_eq!(error(".ORIG x3000\nTRAP x100").message, "trap vector #256 does not fit in trapvect8");
+        assert_eq!(error(".ORIG x3000\n.FILLL 1").hint.as_deref(), Some("did you mean .FILL?"));
+        assert_eq!(error(".ORIG x3000\nLOOP FOO R1").message, "expected an instruction or directive, found FOO");
+        let diagnostic = error(".ORIG x3000\nADDD R1, R1, #1");
+        assert_eq!((diagnostic.message.as_str(), diagnostic.hint.as_deref()), ("unknown instruction ADDD", Some("did you mean ADD?")));
+        assert_eq!(error(".ORIG x3000\nLoop BR LOOP").hint.as_deref(), Some("labels are case sensitive, did you mean Loop?"));
+        assert_eq!(error(".ORIG x3000\nNOT R8, R1").hint.as_deref(), Some("registers are R0 to R7"));
+        assert_eq!(error(".ORIG x3000\n.EXPORT MAIN").message, "exported label MAIN is not defined");
+        assert_eq!(error(".ORIG x3000\n.IMPORT X\nX .FILL 0").message, "X is imported but defined here too");
+        assert_eq!(error(".ORIG x3000\n.BLKW\n.END").message, ".BLKW takes 1 operand, found 0");
+        assert_eq!(error(".ORIG x3000\n.STRINGZ\n.END").message, ".STRINGZ takes 1 operand, found 0");
+        assert_eq!(error(".ORIG x3000\nLBL .BLKW\n.END").message, ".BLKW takes 1 operand, found 0");
+    }
+
+    #[test]
+    fn test_every_error_reported(){
+        let source = "
+            .ORIG x3000
+            ADD R0, R0, #99
+    LOOP    BRp LOP
+            .STRINGZ 5
+            HALT R0
+            .END";
+        let lines: Vec<usize> = assemble(source).unwrap_err().iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_macro_errors(){
+        let source = "
+            .ORIG x3000
+            .MACRO PUSH reg
+            ADD R6, R6, #-1
+            STR reg, R6, #0
+            .ENDM
+            PUSH R1
+            PUSH R9
+            .END";
+        let diagnostics = assemble(source).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!((diagnostic.line, diagnostic.col, diagnostic.len), (5, 17, 3), "on the parameter in the body");
+        assert_eq!(diagnostic.notes, vec![String::from("in expansion of macro PUSH on line 8")]);
+        assert!(diagnostic.render("push.asm", source).ends_with("= note: in expansion of macro PUSH on line 8\n"));
+
+        let program = assemble(".ORIG x3000\n.DEFINE N #3\nADD R0, R0, N\n.END").unwrap();
+        assert_eq!(program.words, vec![0x1023]);
+    }
+}
@@ -0,0 +1,553 @@
+//! The assembler front end: includes, macros, constants and conditional
+//! assembly, resolved before the source is parsed.
+//!
+//! ```text
+//!         .INCLUDE "stack.asm"            ; the lines of stack.asm, relative to this file
+//!         .DEFINE STACK xFE00             ; STACK is replaced by xFE00 from here on
+//!         .MACRO PUSH reg                 ; parameters are replaced where they appear
+//!         ADD R6, R6, #-1
+//!         STR reg, R6, #0
+//!         .ENDM
+//!         .IF DEBUG == 1                  ; numbers compared with == != < <= > >=,
+//!         PUSH R0                         ; a number alone is true when it isn't 0
+//!         .ELSE
+//!         ADD R0, R0, #0
+//!         .ENDIF
+//! ```
+//!
+//! Macros are invoked like instructions (their names aren't case sensitive)
+//! and may be labelled. Labels in a macro body starting with `@` are local
+//! to each expansion, `@LOOP` in the third expansion of MULT is `_MULT_LOOP_3`.
+//!
+//! Every line keeps the place it was written, a problem in an expanded line
+//! is reported in the macro body, with the expansions and includes that led
+//! there as notes.
+
+use super::diagnostic::Diagnostic;
+use super::lexer::{tokenize, Token, TokenKind};
+use super::{check_label, mnemonic, text_of};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The directives handled here.
+pub const DIRECTIVES: [&str; 7] = [".INCLUDE", ".DEFINE", ".MACRO", ".ENDM", ".IF", ".ELSE", ".ENDIF"];
+
+/// How deep macro expansions and includes can nest, deeper is taken for
+/// a macro expanding itself or a file including itself.
+const MAX_DEPTH: usize = 32;
+
+/// Where a line was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// the included file, None for the source being assembled.
+    pub file: Option<String>,
+    pub line: usize,
+    /// the expansions and includes that led to the line, innermost first.
+    pub notes: Vec<String>,
+}
+
+impl Location {
+    /// `diagnostic` moved to this location.
+    pub fn place(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        diagnostic.file = self.file.clone();
+        diagnostic.line = self.line;
+        diagnostic.notes = self.notes.clone();
+        diagnostic
+    }
+}
+
+/// `line 3` or `line 3 of stack.asm`
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "line {} of {}", self.line, file),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// A line after preprocessing, ready to be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub tokens: Vec<Token>,
+    pub location: Location,
+}
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(Vec<Token>, Location)>,
+    /// the .MACRO line and name.
+    location: Location,
+    token: Token,
+    /// where the file defining it is, for the includes in its body.
+    dir: PathBuf,
+}
+
+struct Conditional {
+    location: Location,
+    token: Token,
+    active: bool,
+    /// whether the lines around the .IF are assembled.
+    outer: bool,
+    seen_else: bool,
+}
+
+/// What's open in a file or macro expansion, both have to close what they open.
+#[derive(Default)]
+struct Unit {
+    conditionals: Vec<Conditional>,
+    defining: Option<(String, Macro)>,                                  // upper cased name
+}
+
+impl Unit {
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+}
+
+struct Preprocessor<'a> {
+    diagnostics: &'a mut Vec<Diagnostic>,
+    lines: Vec<Line>,
+    defines: HashMap<String, (Vec<Token>, Location)>,
+    macros: HashMap<String, Macro>,                                     // upper cased name
+    expansions: usize,
+    /// .END was reached, nothing after it is looked at.
+    ended: bool,
+}
+
+/// Resolve the includes, macros, constants and conditionals of `source`,
+/// included files are looked up relative to `dir`.
+pub fn preprocess(source: &str, dir: &Path, diagnostics: &mut Vec<Diagnostic>) -> Vec<Line> {
+    let mut preprocessor = Preprocessor {
+        diagnostics,
+        lines: Vec::new(),
+        defines: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: 0,
+        ended: false,
+    };
+    preprocessor.file(source, None, dir, &[], 0);
+    preprocessor.lines
+}
+
+impl Preprocessor<'_> {
+    fn file(&mut self, source: &str, file: Option<String>, dir: &Path, notes: &[String], depth: usize) {
+        let mut unit = Unit::default();
+        for (i, text) in source.lines().enumerate() {
+            if self.ended {
+                break;                                                  // the rest of the file is ignored
+            }
+            let location = Location { file: file.clone(), line: i + 1, notes: notes.to_vec() };
+            match tokenize(text) {
+                Ok(tokens) => self.line(tokens, location, &mut unit, dir, depth),
+                Err(e) => self.diagnostics.push(location.place(Diagnostic::new(0, e.col, 1, e.message))),
+            }
+        }
+        self.close(unit);
+    }
+
+    fn line(&mut self, tokens: Vec<Token>, location: Location, unit: &mut Unit, dir: &Path, depth: usize) {
+        let directive = match tokens.first() {
+            Some(token) => directive(token),
+            None => return,
+        };
+        if let Some((_, definition)) = &mut unit.defining {
+            match directive.as_deref() {
+                Some(".ENDM") => {
+                    expect_arguments(&tokens, &location, 0, self.diagnostics);
+                    let (name, definition) = unit.defining.take().unwrap();
+                    self.macros.insert(name, definition);
+                }
+                Some(".MACRO") => self.diagnostics.push(error(&location, &tokens[0], String::from("macros can't be defined inside a macro"))
+                    .with_hint(format!("end {} with .ENDM first", text_of(&definition.token)))),
+                _ => definition.body.push((tokens, location)),
+            }
+            return;
+        }
+        match directive.as_deref() {
+            Some(".IF") => {
+                let outer = unit.active();
+                let active = outer && self.condition(&tokens, &location);
+                unit.conditionals.push(Conditional { location, token: tokens[0].clone(), active, outer, seen_else: false });
+                return;
+            }
+            Some(".ELSE") => {
+                expect_arguments(&tokens, &location, 0, self.diagnostics);
+                match unit.conditionals.last_mut() {
+                    Some(conditional) if !conditional.seen_else => {
+                        conditional.active = conditional.outer && !conditional.active;
+                        conditional.seen_else = true;
+                    }
+                    Some(conditional) => self.diagnostics.push(error(&location, &tokens[0], String::from("a second .ELSE for the same .IF"))
+                        .with_hint(format!("the .IF is on {}", conditional.location))),
+                    None => self.diagnostics.push(error(&location, &tokens[0], String::from(".ELSE without .IF"))),
+                }
+                return;
+            }
+            Some(".ENDIF") => {
+                expect_arguments(&tokens, &location, 0, self.diagnostics);
+                if unit.conditionals.pop().is_none() {
+                    self.diagnostics.push(error(&location, &tokens[0], String::from(".ENDIF without .IF")));
+                }
+                return;
+            }
+            _ => {}
+        }
+        if !unit.active() {
+            return;
+        }
+        match directive.as_deref() {
+            Some(".DEFINE") => self.define(&tokens, &location),
+            Some(".MACRO") => unit.defining = self.start_macro(&tokens, location, dir),
+            Some(".ENDM") => self.diagnostics.push(error(&location, &tokens[0], String::from(".ENDM without .MACRO"))),
+            Some(".INCLUDE") => self.include(&tokens, &location, dir, depth),
+            _ => self.statement(tokens, location, depth),
+        }
+    }
+
+    /// Report what's left open at the end of a file or expansion.
+    fn close(&mut self, unit: Unit) {
+        for conditional in unit.conditionals {
+            self.diagnostics.push(error(&conditional.location, &conditional.token, String::from(".IF without .ENDIF"))
+                .with_hint(String::from("close it with .ENDIF in the same file or macro")));
+        }
+        if let Some((_, definition)) = unit.defining {
+            self.diagnostics.push(error(&definition.location, &definition.token, format!("macro {} has no .ENDM", text_of(&definition.token)))
+                .with_hint(String::from("end the definition with .ENDM in the same file")));
+        }
+    }
+
+    /// An instruction or directive for the parser, or a macro to expand.
+    fn statement(&mut self, tokens: Vec<Token>, location: Location, depth: usize) {
+        let tokens = self.substitute(tokens);
+        if let Some(token) = tokens.get(1).filter(|t| directive(t).is_some_and(|d| DIRECTIVES.contains(&d.as_str()))) {
+            self.diagnostics.push(error(&location, &tokens[0], format!("a label can't be placed on {}", text_of(token)))
+                .with_hint(format!("put the label on the line after {}", text_of(token))));
+            return;
+        }
+        // a macro is named by the first word, or the second one after a label
+        let call = (0..tokens.len().min(2))
+            .filter(|&i| i == 0 || mnemonic(&tokens[0]).is_none())
+            .find(|&i| self.macro_name(&tokens[i]).is_some());
+        match call {
+            Some(i) => {
+                if i == 1 {
+                    self.lines.push(Line { tokens: vec![tokens[0].clone()], location: location.clone() });
+                }
+                self.expand(&tokens[i], &tokens[i + 1..], &location, depth);
+            }
+            None => {
+                self.ended = tokens.iter().take(2).any(|t| directive(t).as_deref() == Some(".END"));
+                self.lines.push(Line { tokens, location });
+            }
+        }
+    }
+
+    /// `tokens` with the names given by .DEFINE replaced by their values,
+    /// which keep the place of the name.
+    fn substitute(&self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut out = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            match &token.kind {
+                TokenKind::Word(word) if self.defines.contains_key(word) => {
+                    out.extend(self.defines[word].0.iter().map(|value| Token { col: token.col, len: token.len, ..value.clone() }));
+                }
+                _ => out.push(token),
+            }
+        }
+        out
+    }
+
+    /// `.DEFINE NAME value`
+    fn define(&mut self, tokens: &[Token], location: &Location) {
+        if tokens.len() < 3 {
+            let last = tokens.last().unwrap();
+            self.diagnostics.push(location.place(Diagnostic::new(0, last.col + last.len, 1, String::from(".DEFINE takes a name and a value")))
+                .with_hint(String::from("write it as .DEFINE NAME x3000")));
+            return;
+        }
+        let name = &tokens[1];
+        if let Err(diagnostic) = check_label(0, name) {
+            self.diagnostics.push(location.place(diagnostic));
+            return;
+        }
+        let word = text_of(name);
+        if let Some((_, first)) = self.defines.get(&word) {
+            let diagnostic = error(location, name, format!("{} is defined twice", word))
+                .with_hint(format!("first defined on {}", first));
+            self.diagnostics.push(diagnostic);
+            return;
+        }
+        let value = self.substitute(tokens[2..].to_vec());
+        self.defines.insert(word, (value, location.clone()));
+    }
+
+    /// Whether the lines after `.IF condition` are assembled, a condition
+    /// that can't be worked out is reported and taken as false.
+    fn condition(&mut self, tokens: &[Token], location: &Location) -> bool {
+        let operands = self.substitute(tokens[1..].to_vec());
+        let value = |token: &Token| match &token.kind {
+            TokenKind::Number(n) => Ok(*n),
+            TokenKind::Word(word) => Err(error(location, token, format!("{} is not defined", word))
+                .with_hint(String::from(".IF compares numbers and names given to them with .DEFINE"))),
+            TokenKind::Str(_) => Err(error(location, token, format!("expected a number, found {}", text_of(token)))),
+        };
+        let result = match &operands[..] {
+            [a] => value(a).map(|a| a != 0),
+            [a, op, b] => value(a).and_then(|a| value(b).map(|b| (a, b))).and_then(|(a, b)| match &op.kind {
+                TokenKind::Word(op) if op == "==" => Ok(a == b),
+                TokenKind::Word(op) if op == "!=" => Ok(a != b),
+                TokenKind::Word(op) if op == "<" => Ok(a < b),
+                TokenKind::Word(op) if op == "<=" => Ok(a <= b),
+                TokenKind::Word(op) if op == ">" => Ok(a > b),
+                TokenKind::Word(op) if op == ">=" => Ok(a >= b),
+                _ => Err(error(location, op, format!("unknown comparison {}", text_of(op)))
+                    .with_hint(String::from("compare with ==, !=, <, <=, > or >="))),
+            }),
+            _ => {
+                let last = tokens.last().unwrap();
+                Err(location.place(Diagnostic::new(0, tokens[0].col, last.col + last.len - tokens[0].col, String::from(".IF takes a number or a comparison")))
+                    .with_hint(String::from("write it as .IF DEBUG or .IF SIZE > 10")))
+            }
+        };
+        result.unwrap_or_else(|diagnostic| {
+            self.diagnostics.push(diagnostic);
+            false
+        })
+    }
+
+    /// `.MACRO NAME param, param...`, the definition that starts.
+    fn start_macro(&mut self, tokens: &[Token], location: Location, dir: &Path) -> Option<(String, Macro)> {
+        let name = match tokens.get(1) {
+            Some(name) => name,
+            None => {
+                self.diagnostics.push(error(&location, &tokens[0], String::from(".MACRO takes a name"))
+                    .with_hint(String::from("write it as .MACRO NAME param, param")));
+                return None;
+            }
+        };
+        if mnemonic(name).is_some() {
+            self.diagnostics.push(error(&location, name, format!("{} is an instruction, it can't name a macro", text_of(name))));
+            return None;
+        }
+        let mut params: Vec<String> = Vec::new();
+        for token in &tokens[1..] {
+            if let Err(diagnostic) = check_label(0, token) {
+                self.diagnostics.push(location.place(diagnostic));
+                return None;
+            }
+            let word = text_of(token);
+            if params.contains(&word) {
+                self.diagnostics.push(error(&location, token, format!("parameter {} is named twice", word)));
+                return None;
+            }
+            params.push(word);
+        }
+        params.remove(0);
+        let upper = text_of(name).to_uppercase();
+        if let Some(first) = self.macros.get(&upper) {
+            self.diagnostics.push(error(&location, name, format!("macro {} is defined twice", text_of(name)))
+                .with_hint(format!("first defined on {}", first.location)));
+            return None;
+        }
+        let definition = Macro { params, body: Vec::new(), location, token: name.clone(), dir: dir.to_path_buf() };
+        Some((upper, definition))
+    }
+
+    fn macro_name(&self, token: &Token) -> Option<String> {
+        match &token.kind {
+            TokenKind::Word(word) => Some(word.to_uppercase()).filter(|upper| self.macros.contains_key(upper)),
+            _ => None,
+        }
+    }
+
+    /// The lines of the macro `name` with `args` for its parameters.
+    fn expand(&mut self, name: &Token, args: &[Token], site: &Location, depth: usize) {
+        let definition = self.macros[&self.macro_name(name).unwrap()].clone();
+        let word = text_of(name);
+        if args.len() != definition.params.len() {
+            let (col, len) = match (args.first(), args.last()) {
+                (Some(first), Some(last)) => (first.col, last.col + last.len - first.col),
+                _ => (name.col + name.len, 1),
+            };
+            let found = if args.len() == 1 { "argument" } else { "arguments" };
+            self.diagnostics.push(site.place(Diagnostic::new(0, col, len,
+                format!("macro {} takes {}, found {} {}", word, arguments(definition.params.len()), args.len(), found)))
+                .with_hint(format!("write it as {} {}", word, definition.params.join(", "))));
+            return;
+        }
+        if depth >= MAX_DEPTH {
+            self.diagnostics.push(error(site, name, format!("macro {} nests too deep", word))
+                .with_hint(String::from("a macro can't expand itself, directly or through another macro")));
+            return;
+        }
+        self.expansions += 1;
+        let mut notes = vec![format!("in expansion of macro {} on {}", word, site)];
+        notes.extend(site.notes.iter().cloned());
+        let mut unit = Unit::default();
+        for (tokens, location) in &definition.body {
+            if self.ended {
+                break;
+            }
+            let tokens = tokens.iter().map(|token| match &token.kind {
+                TokenKind::Word(word) => match definition.params.iter().position(|p| p == word) {
+                    Some(i) => Token { col: token.col, len: token.len, ..args[i].clone() },
+                    None => match word.strip_prefix('@') {
+                        Some(local) => Token {
+                            kind: TokenKind::Word(format!("_{}_{}_{}", text_of(&definition.token).to_uppercase(), local, self.expansions)),
+                            ..token.clone()
+                        },
+                        None => token.clone(),
+                    },
+                },
+                _ => token.clone(),
+            }).collect();
+            let location = Location { file: location.file.clone(), line: location.line, notes: notes.clone() };
+            self.line(tokens, location, &mut unit, &definition.dir, depth + 1);
+        }
+        self.close(unit);
+    }
+
+    /// `.INCLUDE "file"`, the lines of the file in place of the directive.
+    fn include(&mut self, tokens: &[Token], location: &Location, dir: &Path, depth: usize) {
+        let name = match &tokens[1..] {
+            [Token { kind: TokenKind::Str(name), .. }] => String::from_utf8_lossy(name).into_owned(),
+            _ => {
+                self.diagnostics.push(error(location, &tokens[0], String::from(".INCLUDE takes a file name"))
+                    .with_hint(String::from("write it as .INCLUDE \"file.asm\"")));
+                return;
+            }
+        };
+        if depth >= MAX_DEPTH {
+            self.diagnostics.push(error(location, &tokens[1], String::from("includes nest too deep"))
+                .with_hint(String::from("a file can't include itself, directly or through another file")));
+            return;
+        }
+        let path = dir.join(&name);
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                self.diagnostics.push(error(location, &tokens[1], format!("can't read {}: {}", path.display(), e)));
+                return;
+            }
+        };
+        let mut notes = vec![format!("included from {}", location)];
+        notes.extend(location.notes.iter().cloned());
+        let parent = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        self.file(&source, Some(path.to_string_lossy().into_owned()), &parent, &notes, depth + 1);
+    }
+}
+
+/// The upper cased directive `token` spells, if it's one.
+fn directive(token: &Token) -> Option<String> {
+    match &token.kind {
+        TokenKind::Word(word) if word.starts_with('.') => Some(word.to_uppercase()),
+        _ => None,
+    }
+}
+
+fn error(location: &Location, token: &Token, message: String) -> Diagnostic {
+    location.place(Diagnostic::new(0, token.col, token.len, message))
+}
+
+fn arguments(count: usize) -> String {
+    match count {
+        0 => String::from("no arguments"),
+        1 => String::from("1 argument"),
+        n => format!("{} arguments", n),
+    }
+}
+
+/// Report anything written after a directive that takes `count` arguments.
+fn expect_arguments(tokens: &[Token], location: &Location, count: usize, diagnostics: &mut Vec<Diagnostic>) {
+    if tokens.len() > count + 1 {
+        let (extra, last) = (&tokens[count + 1], tokens.last().unwrap());
+        diagnostics.push(location.place(Diagnostic::new(0, extra.col, last.col + last.len - extra.col,
+            format!("{} takes {}", text_of(&tokens[0]), arguments(count)))));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(source: &str) -> Result<Vec<String>, Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        let lines = preprocess(source, Path::new(""), &mut diagnostics);
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(lines.iter().map(|line| line.tokens.iter().map(text_of).collect::<Vec<_>>().join(" ")).collect())
+    }
+
+    #[test]
+    fn test_macros(){
+        let source = "
+            .MACRO PUSH reg
+            ADD R6, R6, #-1
+            STR reg, R6, #0
+            .ENDM
+            .MACRO COUNT n
+    @LOOP   ADD n, n, #-1
+            BRp @LOOP
+            .ENDM
+    START   push R1
+            COUNT R2
+            COUNT R3";
+        assert_eq!(lines(source).unwrap(), vec![
+            "START", "ADD R6 R6 #-1", "STR R1 R6 #0",
+            "_COUNT_LOOP_2 ADD R2 R2 #-1", "BRp _COUNT_LOOP_2",
+            "_COUNT_LOOP_3 ADD R3 R3 #-1", "BRp _COUNT_LOOP_3",
+        ]);
+        let diagnostic = lines(".MACRO PUSH reg\nSTR reg, R6, #0\n.ENDM\nPUSH R1, R2").unwrap_err().remove(0);
+        assert_eq!((diagnostic.line, diagnostic.message.as_str()), (4, "macro PUSH takes 1 argument, found 2 arguments"));
+        let diagnostic = lines(".MACRO LOOP\nLOOP\n.ENDM\nLOOP").unwrap_err().remove(0);
+        assert_eq!(diagnostic.message, "macro LOOP nests too deep");
+        assert_eq!(lines(".MACRO PUSH reg\n").unwrap_err()[0].message, "macro PUSH has no .ENDM");
+    }
+
+    #[test]
+    fn test_conditionals(){
+        let source = "
+            .DEFINE DEBUG 1
+            .DEFINE SIZE #20
+            .IF DEBUG
+            .IF SIZE < 10
+            small
+            .ELSE
+            big SIZE
+            .ENDIF
+            .ELSE
+            silent
+            .ENDIF";
+        assert_eq!(lines(source).unwrap(), vec!["big #20"]);
+        assert_eq!(lines(".IF MISSING\n.ENDIF").unwrap_err()[0].message, "MISSING is not defined");
+        assert_eq!(lines(".IF 1\n").unwrap_err()[0].message, ".IF without .ENDIF");
+        assert_eq!(lines(".ENDIF").unwrap_err()[0].message, ".ENDIF without .IF");
+        let diagnostic = lines(".DEFINE A 1\n.DEFINE A 2").unwrap_err().remove(0);
+        assert_eq!(diagnostic.hint.as_deref(), Some("first defined on line 1"));
+    }
+
+    #[test]
+    fn test_locations(){
+        let dir = std::env::temp_dir().join(format!("lc3-include-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.asm"), ".MACRO CLEAR reg\nAND reg, reg, #0\n.ENDM\n").unwrap();
+        let mut diagnostics = Vec::new();
+        let lines = preprocess(".INCLUDE \"lib.asm\"\n\nCLEAR R0\n", &dir, &mut diagnostics);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(diagnostics.is_empty());
+        let lib = dir.join("lib.asm").to_string_lossy().into_owned();
+        assert_eq!(lines[0].location, Location {
+            file: Some(lib),
+            line: 2,
+            notes: vec![String::from("in expansion of macro CLEAR on line 3")],
+        });
+        assert_eq!(lines[0].tokens[1].col, 5, "the argument sits where the parameter is written");
+        assert_eq!(lines[0].location.to_string(), format!("line 2 of {}", dir.join("lib.asm").display()));
+    }
+}
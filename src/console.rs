@@ -0,0 +1,258 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+/// What a non-blocking read found.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// a byte was waiting.
+    Byte(u8),
+    /// nothing typed yet.
+    Waiting,
+    /// the input has run out, nothing will ever come.
+    Eof,
+}
+
+/// Where the machine's keyboard input comes from and display output goes.
+///
+/// The trap routines and the keyboard/display devices only talk to the
+/// outside world through this trait, so the machine can be embedded,
+/// tested or driven from a script.
+pub trait Console {
+    /// Next input byte, None once the input has run out.
+    /// Blocks until there is one.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+    /// Next input byte if one is available right now, never blocks.
+    /// Consoles whose input is always at hand just read it.
+    fn try_read_byte(&mut self) -> io::Result<Input> {
+        Ok(match self.read_byte()? {
+            Some(byte) => Input::Byte(byte),
+            None => Input::Eof,
+        })
+    }
+    /// Output one byte.
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+    /// Make sure everything written so far is visible.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Output every byte of `bytes`.
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for byte in bytes {
+            self.write_byte(*byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read one byte from a reader, None at end of input.
+fn read_one(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut buffer = [0u8; 1];
+    loop {
+        match input.read(&mut buffer) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buffer[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The process' standard input and output.
+///
+/// Input is read straight from file descriptor 0, bypassing the buffer
+/// of io::stdin(), so poll(2) sees exactly what the machine hasn't read yet.
+#[derive(Default)]
+pub struct StdConsole;
+
+#[cfg(unix)]
+impl Console for StdConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buffer = [0u8; 1];
+        loop {
+            let n = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, 1) };
+            match n {
+                0 => return Ok(None),
+                1 => return Ok(Some(buffer[0])),
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn try_read_byte(&mut self) -> io::Result<Input> {
+        let mut fds = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { libc::poll(&mut fds, 1, 0) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted { Ok(Input::Waiting) } else { Err(e) };
+        }
+        if ready == 0 {
+            return Ok(Input::Waiting);
+        }
+        // readable or hung up, either way read won't block
+        Ok(match self.read_byte()? {
+            Some(byte) => Input::Byte(byte),
+            None => Input::Eof,
+        })
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        io::stdout().write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Without poll(2) reads block, polling programs wait for each key.
+#[cfg(not(unix))]
+impl Console for StdConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        read_one(&mut io::stdin())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        io::stdout().write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Input from a buffer, output collected in memory.
+///
+/// The output buffer is shared, keep a handle from `output()` before
+/// handing the console to the machine to look at what the program printed.
+#[derive(Default)]
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl BufferConsole {
+    /// A console that will read `input` and then report end of input.
+    pub fn new(input: &[u8]) -> Self {
+        BufferConsole { input: input.iter().copied().collect(), output: Rc::default() }
+    }
+
+    /// Handle on everything written to the console.
+    pub fn output(&self) -> Rc<RefCell<Vec<u8>>> {
+        Rc::clone(&self.output)
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.borrow_mut().push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Input scripted from any reader (a file, a string), output to any writer.
+pub struct ScriptedConsole<R: Read, W: Write> {
+    pub input: R,
+    pub output: W,
+}
+
+impl<R: Read, W: Write> ScriptedConsole<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        ScriptedConsole { input, output }
+    }
+}
+
+impl<R: Read, W: Write> Console for ScriptedConsole<R, W> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        read_one(&mut self.input)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Another console's input with the output going to any writer, so
+/// redirecting the output keeps the non-blocking reads of StdConsole.
+pub struct RedirectedConsole<C: Console, W: Write> {
+    pub console: C,
+    pub output: W,
+}
+
+impl<C: Console, W: Write> RedirectedConsole<C, W> {
+    pub fn new(console: C, output: W) -> Self {
+        RedirectedConsole { console, output }
+    }
+}
+
+impl<C: Console, W: Write> Console for RedirectedConsole<C, W> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.console.read_byte()
+    }
+
+    fn try_read_byte(&mut self) -> io::Result<Input> {
+        self.console.try_read_byte()
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_console(){
+        let mut console = BufferConsole::new(b"ab");
+        let output = console.output();
+        assert_eq!(console.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(console.try_read_byte().unwrap(), Input::Byte(b'b'));
+        assert_eq!(console.read_byte().unwrap(), None);
+        assert_eq!(console.try_read_byte().unwrap(), Input::Eof);
+        console.write_bytes(b"hi").unwrap();
+        assert_eq!(&*output.borrow(), b"hi");
+    }
+
+    #[test]
+    fn test_scripted_console(){
+        let mut console = ScriptedConsole::new(&b"x"[..], Vec::new());
+        assert_eq!(console.read_byte().unwrap(), Some(b'x'));
+        assert_eq!(console.read_byte().unwrap(), None);
+        console.write_byte(b'!').unwrap();
+        assert_eq!(console.output, b"!");
+    }
+
+    #[test]
+    fn test_redirected_console(){
+        let mut console = RedirectedConsole::new(BufferConsole::new(b"x"), Vec::new());
+        let inner = console.console.output();
+        assert_eq!(console.try_read_byte().unwrap(), Input::Byte(b'x'));
+        assert_eq!(console.read_byte().unwrap(), None);
+        console.write_byte(b'!').unwrap();
+        assert_eq!(console.output, b"!");
+        assert!(inner.borrow().is_empty(), "nothing reaches the inner console");
+    }
+}
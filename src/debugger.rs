@@ -0,0 +1,534 @@
+//! An interactive debugger driving a [`Vm`] one instruction at a time.
+//!
+//! ```text
+//! step [n]                 run n instructions (1), into subroutines and traps
+//! next                     run one instruction, over JSR, JSRR and TRAP
+//! finish                   run until the current subroutine returns
+//! continue                 run until a breakpoint or the end of the program
+//! break <addr|label>       stop before the instruction there, alone lists breakpoints
+//! break LOOP if R0 == 3    stop there only when the condition holds
+//! delete [addr|label]      remove a breakpoint, all of them without an argument
+//! watch COUNT              stop after COUNT is written, alone lists watchpoints
+//! rwatch BUF:10 if R1 > 4  stop after one of the 10 words from BUF is read, if R1 > 4
+//! awatch x4000             stop after x4000 is read or written
+//! watch mem[x4000] > 10    stop once the condition becomes true
+//! unwatch [n]              remove watchpoint n, all of them without an argument
+//! regs                     show the registers
+//! x/16 x3000               show 16 words from x3000, x/16i shows instructions
+//! set R3 = x10             set a register (R0-R7, PC, COND, PSR)
+//! set mem[x4000] = 5       set a word of memory
+//! quit
+//! ```
+//!
+//! Numbers are written x10 (hex), #16 or 16, a label stands for its address.
+//! Conditions are described in [`crate::watch`].
+
+use crate::defs::opcode::Opcode;
+use crate::defs::register::Reg;
+use crate::disassembler::disassemble_memory;
+use crate::defs::memory::AccessKind;
+use crate::symbols::parse_number;
+use crate::vm::{StopReason, Vm};
+use crate::watch::{Condition, WatchKind, Watchpoint};
+use std::io::{self, BufRead, Write};
+
+/// What `help` shows.
+const HELP: &str = "\
+step [n]                 run n instructions (1), into subroutines and traps
+next                     run one instruction, over JSR, JSRR and TRAP
+finish                   run until the current subroutine returns
+continue                 run until a breakpoint or the end of the program
+break <addr|label>       stop before the instruction there, alone lists breakpoints
+break <addr> if <cond>   stop there when the condition holds, like R0 == x41
+delete [addr|label]      remove a breakpoint, all of them without an argument
+watch <addr>[:n]         stop after a write to n words (1), alone lists watchpoints
+rwatch, awatch           the same for reads, for reads and writes
+watch ... if <cond>      only when the condition holds then
+watch <cond>             stop once the condition becomes true, like mem[x4000] > 10
+unwatch [n]              remove watchpoint n, all of them without an argument
+regs                     show the registers
+x/<n>[i] <addr|label>    show n words (or instructions) of memory
+set <reg> = <value>      set R0-R7, PC, COND or PSR
+set mem[<addr>] = <value>
+quit";
+
+/// RET, the end of a subroutine (and of most trap routines).
+const RET: u16 = 0xC1C0;
+
+pub struct Debugger {
+    pub vm: Vm,
+}
+
+impl Debugger {
+    pub fn new(vm: Vm) -> Self {
+        Debugger { vm }
+    }
+
+    /// Read commands from `input` until it ends or `quit`, prompting on `out`.
+    pub fn repl(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        self.show_location(out)?;
+        loop {
+            write!(out, "(lc3) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return writeln!(out);
+            }
+            if !self.command(line.trim(), out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run one command, what it shows goes to `out`. False once asked to quit.
+    pub fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let (name, rest) = match line.split_once(char::is_whitespace) {
+            Some((name, rest)) => (name, rest.trim()),
+            None => (line, ""),
+        };
+        let result = match name {
+            "" => Ok(()),
+            "step" | "s" => self.count(rest).and_then(|n| {
+                let mut left = n;
+                let stop = self.step_until(|_, _, _| {
+                    left -= 1;
+                    left == 0
+                });
+                self.stopped(stop, out).map_err(|e| e.to_string())
+            }),
+            "next" | "n" => {
+                let stop = self.next();
+                self.stopped(stop, out).map_err(|e| e.to_string())
+            }
+            "finish" => {
+                let stop = self.finish();
+                self.stopped(stop, out).map_err(|e| e.to_string())
+            }
+            "continue" | "c" => {
+                let stop = self.vm.run();
+                self.stopped(Some(stop), out).map_err(|e| e.to_string())
+            }
+            "break" | "b" => self.breakpoint(rest, out),
+            "delete" | "d" => self.delete(rest),
+            "watch" | "w" => self.watch(WatchKind::Write, rest, out),
+            "rwatch" => self.watch(WatchKind::Read, rest, out),
+            "awatch" => self.watch(WatchKind::Access, rest, out),
+            "unwatch" => self.unwatch(rest),
+            "regs" | "r" => self.show_registers(out).map_err(|e| e.to_string()),
+            "set" => self.set(rest),
+            "help" | "h" => writeln!(out, "{}", HELP).map_err(|e| e.to_string()),
+            "quit" | "q" => return Ok(false),
+            _ if name == "x" || name.starts_with("x/") => self.examine(&name[1..], rest, out),
+            _ => Err(format!("unknown command {}, try help", name)),
+        };
+        if let Err(message) = result {
+            writeln!(out, "{}", message)?;
+        }
+        Ok(true)
+    }
+
+    /// Step until `done` says so (it's given the machine, the address and
+    /// the instruction just run), the program stops or a breakpoint or
+    /// watchpoint is reached. A breakpoint at the first instruction doesn't count.
+    fn step_until(&mut self, mut done: impl FnMut(&Vm, u16, u16) -> bool) -> Option<StopReason> {
+        let mut first = true;
+        loop {
+            let pc = self.vm.reg[Reg::R_PC];
+            if !first && self.vm.at_breakpoint() {
+                return Some(StopReason::Breakpoint(pc));
+            }
+            first = false;
+            let instr = self.vm.memory.peek(pc);
+            if let Some(reason) = self.vm.step() {
+                return Some(reason);
+            }
+            if done(&self.vm, pc, instr) {
+                return None;
+            }
+        }
+    }
+
+    /// One instruction, a call runs until it returns.
+    fn next(&mut self) -> Option<StopReason> {
+        let mut depth = 0;
+        self.step_until(|vm, pc, instr| {
+            if entered_call(vm, pc, instr) {
+                depth += 1;
+            } else if is_return(instr) {
+                depth -= 1;
+            }
+            depth <= 0
+        })
+    }
+
+    /// Run until the subroutine (or trap or interrupt routine) running now returns.
+    fn finish(&mut self) -> Option<StopReason> {
+        let mut depth = 0;
+        self.step_until(|vm, pc, instr| {
+            if is_return(instr) {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            } else if entered_call(vm, pc, instr) {
+                depth += 1;
+            }
+            false
+        })
+    }
+
+    /// Report why the machine stopped, if it did, and where it is.
+    fn stopped(&self, stop: Option<StopReason>, out: &mut dyn Write) -> io::Result<()> {
+        let symbols = &self.vm.symbols;
+        let pc = self.vm.reg[Reg::R_PC];
+        let report = match stop {
+            None => None,
+            Some(StopReason::Breakpoint(at)) => Some(format!("breakpoint at {}", symbols.describe(at))),
+            Some(StopReason::Watchpoint(index, Some(access))) => Some(match access.kind {
+                AccessKind::Read => format!("watchpoint {}: read x{:04X} from {}", index + 1, access.value, symbols.describe(access.address)),
+                AccessKind::Write => format!("watchpoint {}: wrote x{:04X} to {}", index + 1, access.value, symbols.describe(access.address)),
+            }),
+            Some(StopReason::Watchpoint(index, None)) => {
+                Some(format!("watchpoint {}: {}", index + 1, self.vm.watchpoints[index].describe(symbols)))
+            }
+            Some(StopReason::Halted) => Some(String::from("program halted")),
+            Some(StopReason::Fault(e)) => Some(format!("fault at {}: {}", symbols.describe(pc), e)),
+            Some(StopReason::InfiniteLoop(at)) => Some(format!("infinite loop at {}", symbols.describe(at))),
+            Some(StopReason::InputExhausted) => Some(format!("input exhausted at {}", symbols.describe(pc))),
+            Some(StopReason::Timeout) => Some(format!("timed out at {}", symbols.describe(pc))),
+            Some(StopReason::BudgetExhausted) => Some(format!("instruction budget exhausted at {}", symbols.describe(pc))),
+        };
+        if let Some(report) = report {
+            writeln!(out, "{}", report)?;
+        }
+        if self.vm.running {
+            self.show_location(out)?;
+        }
+        Ok(())
+    }
+
+    /// The instruction about to run.
+    fn show_location(&self, out: &mut dyn Write) -> io::Result<()> {
+        let pc = self.vm.reg[Reg::R_PC];
+        let line = &disassemble_memory(&self.vm.memory, pc, 1, &self.vm.symbols)[0];
+        writeln!(out, "=> {}", line)
+    }
+
+    fn show_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        let reg = &self.vm.reg;
+        for row in 0..2 {
+            let cells: Vec<String> = (row * 4..row * 4 + 4).map(|r| format!("R{} x{:04X}", r, reg[r])).collect();
+            writeln!(out, "{}", cells.join("  "))?;
+        }
+        let cond = reg[Reg::R_COND];
+        let flags: String = [(0b100, 'N'), (0b010, 'Z'), (0b001, 'P')].iter()
+            .map(|&(bit, name)| if cond & bit != 0 { name } else { '-' })
+            .collect();
+        let pc = reg[Reg::R_PC];
+        let mode = if self.vm.psr.user_mode { "user" } else { "supervisor" };
+        let label = self.vm.symbols.label(pc).map(|label| format!(" {}", label)).unwrap_or_default();
+        writeln!(out, "PC x{:04X}{}  PSR x{:04X} {} {} priority {}", pc, label,
+            self.vm.psr.to_u16(reg), flags, mode, self.vm.psr.priority)
+    }
+
+    fn breakpoint(&mut self, rest: &str, out: &mut dyn Write) -> Result<(), String> {
+        if rest.is_empty() {
+            let mut addresses: Vec<&u16> = self.vm.breakpoints.iter().collect();
+            addresses.sort();
+            if addresses.is_empty() {
+                return writeln!(out, "no breakpoints").map_err(|e| e.to_string());
+            }
+            return addresses.iter()
+                .try_for_each(|&&address| writeln!(out, "{}", self.describe_breakpoint(address)))
+                .map_err(|e| e.to_string());
+        }
+        let (location, condition) = split_condition(rest);
+        let address = self.value(location)?;
+        match condition {
+            Some(condition) => {
+                let condition = Condition::parse(condition, &self.vm.symbols)?;
+                self.vm.break_conditions.insert(address, condition);
+            }
+            None => {
+                self.vm.break_conditions.remove(&address);
+            }
+        }
+        self.vm.breakpoints.insert(address);
+        writeln!(out, "{}", self.describe_breakpoint(address)).map_err(|e| e.to_string())
+    }
+
+    fn describe_breakpoint(&self, address: u16) -> String {
+        let mut text = format!("breakpoint at {}", self.vm.symbols.describe(address));
+        if let Some(condition) = self.vm.break_conditions.get(&address) {
+            text += &format!(" if {}", condition);
+        }
+        text
+    }
+
+    fn delete(&mut self, rest: &str) -> Result<(), String> {
+        if rest.is_empty() {
+            self.vm.breakpoints.clear();
+            self.vm.break_conditions.clear();
+            return Ok(());
+        }
+        let address = self.value(rest)?;
+        if !self.vm.breakpoints.remove(&address) {
+            return Err(format!("no breakpoint at {}", self.vm.symbols.describe(address)));
+        }
+        self.vm.break_conditions.remove(&address);
+        Ok(())
+    }
+
+    /// `watch COUNT`, `rwatch BUF:10 if R1 > 4` or `watch R0 == x41`,
+    /// alone lists the watchpoints.
+    fn watch(&mut self, kind: WatchKind, rest: &str, out: &mut dyn Write) -> Result<(), String> {
+        if rest.is_empty() {
+            let result = if self.vm.watchpoints.is_empty() {
+                writeln!(out, "no watchpoints")
+            } else {
+                self.vm.watchpoints.iter().enumerate().try_for_each(|(index, watchpoint)| {
+                    writeln!(out, "watchpoint {}: {}", index + 1, watchpoint.describe(&self.vm.symbols))
+                })
+            };
+            return result.map_err(|e| e.to_string());
+        }
+        let (location, condition) = split_condition(rest);
+        let condition = condition.map(|condition| Condition::parse(condition, &self.vm.symbols)).transpose()?;
+        let watchpoint = match (condition, Condition::parse(location, &self.vm.symbols)) {
+            (None, Ok(_)) if kind != WatchKind::Write => return Err(String::from("only watch takes a condition alone")),
+            (None, Ok(alone)) => Watchpoint::condition(alone, &self.vm.reg, &self.vm.memory),
+            (condition, _) => {
+                let (start, count) = match location.split_once(':') {
+                    Some((start, count)) => (self.value(start.trim())?, self.count(count.trim())?),
+                    None => (self.value(location)?, 1),
+                };
+                if count > 0x10000 - start as usize {
+                    return Err(format!("{} words from x{:04X} run past the end of memory", count, start));
+                }
+                Watchpoint::Memory { start, end: start + (count - 1) as u16, kind, condition }
+            }
+        };
+        self.vm.watchpoints.push(watchpoint);
+        let index = self.vm.watchpoints.len();
+        writeln!(out, "watchpoint {}: {}", index, self.vm.watchpoints[index - 1].describe(&self.vm.symbols))
+            .map_err(|e| e.to_string())
+    }
+
+    fn unwatch(&mut self, rest: &str) -> Result<(), String> {
+        if rest.is_empty() {
+            self.vm.watchpoints.clear();
+            return Ok(());
+        }
+        let n = self.count(rest)?;
+        if n > self.vm.watchpoints.len() {
+            return Err(format!("no watchpoint {}", n));
+        }
+        self.vm.watchpoints.remove(n - 1);
+        Ok(())
+    }
+
+    /// `x/16 x3000` shows words 8 to a line, `x/4i LOOP` instructions.
+    fn examine(&self, format: &str, rest: &str, out: &mut dyn Write) -> Result<(), String> {
+        let format = format.strip_prefix('/').unwrap_or(format);
+        let (count, instructions) = match format.strip_suffix('i') {
+            Some(count) => (count, true),
+            None => (format, false),
+        };
+        let count = if count.is_empty() { 1 } else { self.count(count)? };
+        let start = if rest.is_empty() { self.vm.reg[Reg::R_PC] } else { self.value(rest)? };
+        let result = if instructions {
+            disassemble_memory(&self.vm.memory, start, count as u16, &self.vm.symbols).iter()
+                .try_for_each(|line| writeln!(out, "{}", line))
+        } else {
+            (0..count).step_by(8).try_for_each(|row| {
+                let address = start.wrapping_add(row as u16);
+                let words: Vec<String> = (row..count.min(row + 8))
+                    .map(|i| format!("x{:04X}", self.vm.memory.peek(start.wrapping_add(i as u16))))
+                    .collect();
+                writeln!(out, "x{:04X}: {}", address, words.join(" "))
+            })
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    /// `R3 = x10`, `PC = LOOP` or `mem[x4000] = 5`.
+    fn set(&mut self, rest: &str) -> Result<(), String> {
+        let (target, value) = rest.split_once('=').ok_or("write it as set R3 = x10 or set mem[x4000] = 5")?;
+        let (target, value) = (target.trim(), self.value(value.trim())?);
+        if let Some(address) = target.strip_prefix("mem[").and_then(|t| t.strip_suffix(']')) {
+            let address = self.value(address.trim())?;
+            self.vm.memory[address] = value;
+            return Ok(());
+        }
+        match target.to_uppercase().as_str() {
+            "PC" => self.vm.reg[Reg::R_PC] = value,
+            "COND" | "CC" => self.vm.reg[Reg::R_COND] = value & 0b111,
+            "PSR" => self.vm.psr.load(&mut self.vm.reg, value),
+            name => match name.strip_prefix('R').and_then(|r| r.parse::<u16>().ok()).filter(|r| *r < 8) {
+                Some(r) => self.vm.reg[r] = value,
+                None => return Err(format!("unknown register {}, registers are R0 to R7, PC, COND and PSR", target)),
+            },
+        }
+        Ok(())
+    }
+
+    /// A number (x10, #16, 16, -1) or the address of a label.
+    fn value(&self, text: &str) -> Result<u16, String> {
+        parse_number(text)
+            .or_else(|| self.vm.symbols.address(text))
+            .ok_or_else(|| format!("{} is neither a number nor a known label", text))
+    }
+
+    fn count(&self, text: &str) -> Result<usize, String> {
+        if text.is_empty() {
+            return Ok(1);
+        }
+        match parse_number(text) {
+            Some(n) if n > 0 => Ok(n as usize),
+            _ => Err(format!("expected a count, found {}", text)),
+        }
+    }
+}
+
+/// `LOOP if R0 == 3` as the location and the condition.
+fn split_condition(text: &str) -> (&str, Option<&str>) {
+    match text.split_once(" if ") {
+        Some((location, condition)) => (location.trim(), Some(condition.trim())),
+        None => (text, None),
+    }
+}
+
+/// Whether `instr`, just run at `pc`, went into a subroutine or trap routine
+/// (a builtin trap is serviced without leaving the instruction).
+fn entered_call(vm: &Vm, pc: u16, instr: u16) -> bool {
+    let call = matches!(Opcode::from_u16(instr >> 12), Opcode::OP_JSR | Opcode::OP_TRAP);
+    call && vm.reg[Reg::R_PC] != pc.wrapping_add(1)
+}
+
+fn is_return(instr: u16) -> bool {
+    instr == RET || matches!(Opcode::from_u16(instr >> 12), Opcode::OP_RTI)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+    use crate::defs::traps::TrapMode;
+
+    /// Counts R1 down in a subroutine, R0 ends up holding how many times it ran.
+    fn debugger() -> Debugger {
+        let program = assemble("
+            .ORIG x3000
+    MAIN    AND R0, R0, #0
+            LD R1, COUNT
+    LOOP    JSR DEC
+            ADD R0, R0, #1
+            ADD R1, R1, #0
+            BRp LOOP
+            HALT
+    DEC     ADD R1, R1, #-1
+            RET
+    COUNT   .FILL 3
+            .END").unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.load_images(&[program.image()], 0).unwrap();
+        vm.symbols = program.symbol_table();
+        Debugger::new(vm)
+    }
+
+    fn run(debugger: &mut Debugger, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(debugger.command(line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_stepping(){
+        let mut debugger = debugger();
+        assert_eq!(run(&mut debugger, "step 2"), "=> x3002  4804  LOOP             JSR DEC\n");
+        assert_eq!(run(&mut debugger, "step"), "=> x3007  127F  DEC              ADD R1, R1, #-1\n");
+        assert_eq!(run(&mut debugger, "finish"), "=> x3003  1021                   ADD R0, R0, #1\n");
+        assert_eq!(debugger.vm.reg[Reg::R_R1], 2);
+        run(&mut debugger, "step 3");
+        assert_eq!(run(&mut debugger, "next"), "=> x3003  1021                   ADD R0, R0, #1\n");
+        assert_eq!(debugger.vm.reg[Reg::R_R1], 1, "stepped over DEC");
+        assert_eq!(run(&mut debugger, "continue"), "program halted\n");
+        assert_eq!(debugger.vm.reg[Reg::R_R0], 3);
+    }
+
+    #[test]
+    fn test_breakpoints(){
+        let mut debugger = debugger();
+        assert_eq!(run(&mut debugger, "break DEC"), "breakpoint at DEC\n");
+        assert_eq!(run(&mut debugger, "c"), "breakpoint at DEC\n=> x3007  127F  DEC              ADD R1, R1, #-1\n");
+        assert_eq!(run(&mut debugger, "c"), "breakpoint at DEC\n=> x3007  127F  DEC              ADD R1, R1, #-1\n");
+        assert_eq!(debugger.vm.reg[Reg::R_R0], 1);
+        assert_eq!(run(&mut debugger, "next"), "=> x3008  C1C0                   RET\n", "no call to step over");
+        assert_eq!(run(&mut debugger, "b x3001"), "breakpoint at MAIN+1\n");
+        assert_eq!(run(&mut debugger, "b"), "breakpoint at MAIN+1\nbreakpoint at DEC\n");
+        assert_eq!(run(&mut debugger, "delete MAIN"), "no breakpoint at MAIN\n");
+        run(&mut debugger, "delete");
+        assert_eq!(run(&mut debugger, "c"), "program halted\n");
+    }
+
+    #[test]
+    fn test_watchpoints(){
+        let mut debugger = debugger();
+        assert_eq!(run(&mut debugger, "rwatch COUNT"), "watchpoint 1: read COUNT\n");
+        assert_eq!(run(&mut debugger, "c"), "watchpoint 1: read x0003 from COUNT\n=> x3002  4804  LOOP             JSR DEC\n");
+        run(&mut debugger, "unwatch 1");
+        assert_eq!(run(&mut debugger, "watch R1 == 1"), "watchpoint 1: R1 == 1\n");
+        assert_eq!(run(&mut debugger, "awatch x4000:16 if R0 > 1"), "watchpoint 2: access x4000:16 if R0 > 1\n");
+        assert_eq!(run(&mut debugger, "c"), "watchpoint 1: R1 == 1\n=> x3008  C1C0                   RET\n");
+        assert_eq!(debugger.vm.reg[Reg::R_R0], 1);
+        assert_eq!(run(&mut debugger, "watch"), "watchpoint 1: R1 == 1\nwatchpoint 2: access x4000:16 if R0 > 1\n");
+        assert_eq!(run(&mut debugger, "rwatch R0 == 1"), "only watch takes a condition alone\n");
+        assert_eq!(run(&mut debugger, "unwatch 3"), "no watchpoint 3\n");
+        run(&mut debugger, "unwatch");
+
+        assert_eq!(run(&mut debugger, "break LOOP if R0 == 2"), "breakpoint at LOOP if R0 == 2\n");
+        assert_eq!(run(&mut debugger, "b LOOP if R0 >"), "R0 > is missing an operand\n");
+        assert_eq!(run(&mut debugger, "c"), "breakpoint at LOOP\n=> x3002  4804  LOOP             JSR DEC\n");
+        assert_eq!(debugger.vm.reg[Reg::R_R0], 2);
+        assert_eq!(run(&mut debugger, "b"), "breakpoint at LOOP if R0 == 2\n");
+        assert_eq!(run(&mut debugger, "c"), "program halted\n", "R0 is 3 the next time");
+    }
+
+    #[test]
+    fn test_inspection(){
+        let mut debugger = debugger();
+        run(&mut debugger, "set R3 = x10");
+        run(&mut debugger, "set mem[x4000] = 5");
+        run(&mut debugger, "set pc = LOOP");
+        assert_eq!(debugger.vm.reg[Reg::R_R3], 0x10);
+        assert_eq!(debugger.vm.memory[0x4000], 5);
+        assert_eq!(run(&mut debugger, "regs"), "\
+R0 x0000  R1 x0000  R2 x0000  R3 x0010
+R4 x0000  R5 x0000  R6 x3000  R7 x0000
+PC x3002 LOOP  PSR x0002 -Z- supervisor priority 0\n");
+        assert_eq!(run(&mut debugger, "x/10 MAIN"), "\
+x3000: x5020 x2207 x4804 x1021 x1260 x03FC xF025 x127F
+x3008: xC1C0 x0003\n");
+        assert_eq!(run(&mut debugger, "x/2i DEC"), "\
+x3007  127F  DEC              ADD R1, R1, #-1
+x3008  C1C0                   RET\n");
+        assert_eq!(run(&mut debugger, "set R9 = 1"), "unknown register R9, registers are R0 to R7, PC, COND and PSR\n");
+        assert_eq!(run(&mut debugger, "x/4 NOWHERE"), "NOWHERE is neither a number nor a known label\n");
+        assert!(!debugger.command("quit", &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn test_next_over_trap(){
+        let program = assemble(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"hi\"\n.END").unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.trap_mode = TrapMode::Authentic;
+        vm.load_images(&[program.image(), crate::loader::Image::lc3os()], 0).unwrap();
+        let mut debugger = Debugger::new(vm);
+        run(&mut debugger, "step");
+        assert_eq!(run(&mut debugger, "next"), "=> x3002  F025                   TRAP x25\n");
+        run(&mut debugger, "step");
+        assert_ne!(debugger.vm.reg[Reg::R_PC], 0x3003, "stepped into the HALT routine");
+    }
+}
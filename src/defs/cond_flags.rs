@@ -1,4 +1,5 @@
 
+/// Condition flags stored in the COND register.
 #[allow(non_camel_case_types)]
 pub enum Cond_flags{
     FL_POS = 1,      // Positive -> 001
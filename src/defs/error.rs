@@ -0,0 +1,72 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can stop the machine short of a HALT.
+#[derive(Debug)]
+pub enum VmError {
+    /// the instruction uses the reserved opcode 1101.
+    ReservedInstruction(u16),
+    /// a TRAP to a vector the builtin routines don't implement.
+    UnknownTrap(u16),
+    /// a privileged instruction (RTI) ran in user mode.
+    PrivilegeViolation,
+    /// user mode code touched system space or a device register.
+    AccessViolation(u16),
+    /// the program asked for input after the input ran out.
+    InputEof,
+    /// reading input or writing output failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::ReservedInstruction(instr) => write!(f, "reserved instruction x{:04X}", instr),
+            VmError::UnknownTrap(vector) => write!(f, "unknown trap vector x{:02X}", vector),
+            VmError::PrivilegeViolation => write!(f, "privilege mode violation"),
+            VmError::AccessViolation(address) => write!(f, "access control violation at x{:04X}", address),
+            VmError::InputEof => write!(f, "end of input"),
+            VmError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl From<io::Error> for VmError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => VmError::InputEof,
+            _ => VmError::Io(e),
+        }
+    }
+}
+
+/// io::Error isn't comparable, two I/O failures are equal if their kinds are.
+impl PartialEq for VmError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (VmError::ReservedInstruction(a), VmError::ReservedInstruction(b)) => a == b,
+            (VmError::UnknownTrap(a), VmError::UnknownTrap(b)) => a == b,
+            (VmError::PrivilegeViolation, VmError::PrivilegeViolation) => true,
+            (VmError::AccessViolation(a), VmError::AccessViolation(b)) => a == b,
+            (VmError::InputEof, VmError::InputEof) => true,
+            (VmError::Io(a), VmError::Io(b)) => a.kind() == b.kind(),
+            _ => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io_error(){
+        let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "eof");
+        assert_eq!(VmError::from(eof), VmError::InputEof);
+        let broken = io::Error::new(io::ErrorKind::BrokenPipe, "pipe");
+        assert!(matches!(VmError::from(broken), VmError::Io(_)));
+    }
+}
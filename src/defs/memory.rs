@@ -1,19 +1,124 @@
+use crate::defs::error::VmError;
+use crate::devices::Devices;
+use std::cell::RefCell;
 use std::ops::{Index, IndexMut};
 
+/// Size of the LC3 address space in words, every u16 is a valid address.
+pub const MEMORY_SIZE: usize = 1 << 16;
+
+/// First address of user space, everything below is system space.
+pub const USER_SPACE_START: u16 = 0x3000;
+/// First address of the device register space.
+pub const DEVICE_SPACE_START: u16 = 0xFE00;
+
+/// Whether an access read or wrote the word.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A word read or written by an instruction or a trap routine, the value
+/// is the one read or the one written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub address: u16,
+    pub kind: AccessKind,
+    pub value: u16,
+}
 
 /// Random Access Memory RAM struct.
 /// implements Index and IndexMut trait to facilitate indexing with u16
 /// and Reg enum (to use PC)
+///
+/// Indexing decodes the address, the device registers (KBSR, KBDR, DSR,
+/// DDR and MCR) are served by `devices` and everything else by RAM.
+///
+/// Instructions go through `read` and `write`, which also enforce access
+/// control: while `user_mode` is set (the machine mirrors PSR[15] into it)
+/// system space and the device registers are off limits.
+///
+/// While `observe` is set every access made through `read`, `write` and
+/// `read_system` is recorded, the machine collects them with `take_accesses`
+/// to check its watchpoints.
 pub struct Memory {
-    pub size: usize,
     pub memory: Vec<u16>,
+    pub devices: Devices,
+    pub user_mode: bool,
+    pub observe: bool,
+    accesses: RefCell<Vec<Access>>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
-    pub fn new(size: usize) -> Self {
+    /// Create the whole address space, MEMORY_SIZE words all set to zero.
+    pub fn new() -> Self {
         Self {
-            size,
-            memory: vec![0; size]
+            memory: vec![0; MEMORY_SIZE],
+            devices: Devices::default(),
+            user_mode: false,
+            observe: false,
+            accesses: RefCell::default(),
+        }
+    }
+
+    /// Fail with an access control violation if the running mode can't touch `address`.
+    pub fn check_access(&self, address: u16) -> Result<(), VmError> {
+        if self.user_mode && !(USER_SPACE_START..DEVICE_SPACE_START).contains(&address) {
+            return Err(VmError::AccessViolation(address));
+        }
+        Ok(())
+    }
+
+    /// Look at a word without any of the side effects of reading it, for
+    /// debuggers and disassemblers.
+    pub fn peek(&self, address: u16) -> u16 {
+        self.devices.peek(address).unwrap_or(self.memory[address as usize])
+    }
+
+    /// Fetch the word of the next instruction, not an observed access.
+    pub fn fetch(&self, address: u16) -> Result<u16, VmError> {
+        self.check_access(address)?;
+        Ok(self[address])
+    }
+
+    /// Read a word on behalf of an instruction.
+    pub fn read(&self, address: u16) -> Result<u16, VmError> {
+        self.check_access(address)?;
+        let value = self[address];
+        self.record(address, AccessKind::Read, value);
+        Ok(value)
+    }
+
+    /// Read a word on behalf of a builtin trap routine, which runs with
+    /// supervisor rights so nothing is off limits.
+    pub fn read_system(&self, address: u16) -> u16 {
+        let value = self[address];
+        self.record(address, AccessKind::Read, value);
+        value
+    }
+
+    /// Write a word on behalf of an instruction.
+    pub fn write(&mut self, address: u16, value: u16) -> Result<(), VmError> {
+        self.check_access(address)?;
+        self[address] = value;
+        self.record(address, AccessKind::Write, value);
+        Ok(())
+    }
+
+    /// The accesses recorded since the last call, oldest first.
+    pub fn take_accesses(&self) -> Vec<Access> {
+        self.accesses.take()
+    }
+
+    fn record(&self, address: u16, kind: AccessKind, value: u16) {
+        if self.observe {
+            self.accesses.borrow_mut().push(Access { address, kind, value });
         }
     }
 }
@@ -21,12 +126,77 @@ impl Memory {
 impl Index<u16> for Memory {
     type Output = u16;
     fn index(&self, index: u16) -> &Self::Output {
-        &self.memory[index as usize]
+        match self.devices.register(index) {
+            Some(register) => register,
+            None => &self.memory[index as usize],
+        }
     }
 }
 
 impl IndexMut<u16> for Memory {
     fn index_mut(&mut self, index: u16) -> &mut Self::Output {
-        &mut self.memory[index as usize]        
+        match self.devices.register_mut(index) {
+            Some(register) => register,
+            None => &mut self.memory[index as usize],
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::*;
+
+    #[test]
+    fn test_device_decoding(){
+        let mut memory = Memory::new();
+        memory[MR_DDR] = b'!' as u16;
+        assert_eq!(memory.memory[MR_DDR as usize], 0, "device writes don't reach RAM");
+        assert_eq!(memory.devices.take_output(), Some(b'!'));
+        assert_eq!(memory[MR_DSR], STATUS_READY);
+        assert_eq!(memory[MR_MCR], MCR_CLOCK);
+
+        memory[0xFE08] = 5;
+        assert_eq!(memory.memory[0xFE08], 5, "unmapped addresses are RAM");
+        memory[0xFFFF] = 6;
+        assert_eq!(memory[0xFFFF], 6, "the last address is there too");
+
+        memory.devices.press_key(b'a');
+        assert_eq!(memory.peek(MR_KBDR), b'a' as u16);
+        assert_eq!(memory.peek(0xFE08), 5);
+        memory.devices.update();
+        assert!(memory.devices.key_ready(), "peeking doesn't consume the key");
+    }
+
+    #[test]
+    fn test_access_control(){
+        let mut memory = Memory::new();
+        assert_eq!(memory.read(0x0200), Ok(0), "supervisor reads system space");
+        memory.user_mode = true;
+        assert_eq!(memory.read(0x3000), Ok(0));
+        assert_eq!(memory.write(0xFDFF, 1), Ok(()));
+        assert_eq!(memory.read(0x2FFF), Err(VmError::AccessViolation(0x2FFF)));
+        assert_eq!(memory.write(MR_DDR, 1), Err(VmError::AccessViolation(MR_DDR)));
+        assert_eq!(memory.devices.take_output(), None, "denied write never happens");
+    }
+
+    #[test]
+    fn test_observe(){
+        let mut memory = Memory::new();
+        memory.read(0x3000).unwrap();
+        assert!(memory.take_accesses().is_empty(), "only recorded while observed");
+        memory.observe = true;
+        memory.write(0x4000, 7).unwrap();
+        memory.read(0x4000).unwrap();
+        memory.read_system(0x0200);
+        memory.fetch(0x3000).unwrap();
+        memory.peek(0x4000);
+        assert_eq!(memory.take_accesses(), vec![
+            Access { address: 0x4000, kind: AccessKind::Write, value: 7 },
+            Access { address: 0x4000, kind: AccessKind::Read, value: 7 },
+            Access { address: 0x0200, kind: AccessKind::Read, value: 0 },
+        ]);
+        assert!(memory.take_accesses().is_empty());
     }
 }
@@ -1,5 +1,10 @@
+//! Definitions of the LC3 machine: memory, registers, the processor status register, opcodes,
+//! trap vectors, condition flags and the errors the machine can raise.
+
 pub mod memory;
 pub mod register;
 pub mod traps;
 pub mod cond_flags;
-pub mod opcode;
\ No newline at end of file
+pub mod opcode;
+pub mod psr;
+pub mod error;
\ No newline at end of file
@@ -1,3 +1,4 @@
+/// Instruction opcodes, the four most significant bits of an instruction.
 #[allow(non_camel_case_types)]
 pub enum Opcode {
     OP_BR  = 0,    // branch
@@ -8,19 +9,21 @@ pub enum Opcode {
     OP_AND = 5,    // bitwise and
     OP_LDR = 6,    // load register
     OP_STR = 7,    // store register
-   // OP_RTI = 8,    // unused
+    OP_RTI = 8,    // return from interrupt
     OP_NOT = 9,    // bitwise not
     OP_LDI = 10,   // load indirect
     OP_STI = 11,   // store indirect
     OP_JMP = 12,   // jump
-   // OP_RES = 13,   // reserved (unused)
+    OP_RES = 13,   // reserved (unused)
     OP_LEA = 14,   // load effective address
     OP_TRAP = 15    // execute trap
 }
 
 impl Opcode {
+    /// Opcode for the value of bits [15:12], only the low four bits of
+    /// `value` count so every value has one.
     pub fn from_u16(value: u16) -> Self {
-        match value {
+        match value & 0xF {
             0   => Self::OP_BR,
             1   => Self::OP_ADD,
             2   => Self::OP_LD,
@@ -29,14 +32,14 @@ impl Opcode {
             5   => Self::OP_AND,
             6   => Self::OP_LDR,
             7   => Self::OP_STR,
-          //  8   => Self::OP_RTI,
+            8   => Self::OP_RTI,
             9   => Self::OP_NOT,
             10  => Self::OP_LDI,
             11  => Self::OP_STI,
             12  => Self::OP_JMP,
-          //  13  => Self::OP_RES,
+            13  => Self::OP_RES,
             14  => Self::OP_LEA,
-            _   => Self::OP_TRAP
+            _   => Self::OP_TRAP,                                      // 15
         }
     }
-}
\ No newline at end of file
+}
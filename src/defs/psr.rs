@@ -0,0 +1,109 @@
+use crate::defs::register::*;
+
+/// PSR[15], set while the machine runs in user mode.
+pub const PSR_USER: u16 = 1 << 15;
+
+/// Default supervisor stack pointer, the stack grows down from the top of
+/// system space (like the stock LC3 OS sets it).
+pub const SSP_START: u16 = 0x3000;
+
+/// Processor Status Register.
+///
+/// PSR[15] is the privilege mode (0 supervisor, 1 user), PSR[10:8] the
+/// priority level and PSR[2:0] the condition codes. The condition codes
+/// are kept in the COND register so they're folded in when the PSR is read
+/// and split out when it's written.
+///
+/// R6 is the stack pointer of the running mode, the stack pointer of the
+/// other mode is banked in saved_ssp/saved_usp until the mode changes.
+#[derive(Clone, Copy)]
+pub struct Psr {
+    pub user_mode: bool,
+    pub priority: u16,
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+}
+
+impl Default for Psr {
+    fn default() -> Self {
+        Psr { user_mode: false, priority: 0, saved_ssp: SSP_START, saved_usp: 0 }
+    }
+}
+
+impl Psr {
+    /// The PSR as a word, condition codes taken from COND.
+    pub fn to_u16(&self, reg: &Register) -> u16 {
+        let privilege = if self.user_mode { PSR_USER } else { 0 };
+        privilege | (self.priority & 0b111) << 8 | (reg[Reg::R_COND] & 0b111)
+    }
+
+    /// Write the PSR from a word, condition codes go to COND.
+    /// Switches stacks if the privilege mode changes.
+    pub fn load(&mut self, reg: &mut Register, value: u16) {
+        if value & PSR_USER != 0 {
+            self.enter_user(reg);
+        } else {
+            self.enter_supervisor(reg);
+        }
+        self.priority = (value >> 8) & 0b111;
+        reg[Reg::R_COND] = value & 0b111;
+    }
+
+    /// Switch to supervisor mode, R6 becomes the supervisor stack pointer.
+    pub fn enter_supervisor(&mut self, reg: &mut Register) {
+        if self.user_mode {
+            self.saved_usp = reg[Reg::R_R6];
+            reg[Reg::R_R6] = self.saved_ssp;
+            self.user_mode = false;
+        }
+    }
+
+    /// Switch to user mode, R6 becomes the user stack pointer.
+    pub fn enter_user(&mut self, reg: &mut Register) {
+        if !self.user_mode {
+            self.saved_ssp = reg[Reg::R_R6];
+            reg[Reg::R_R6] = self.saved_usp;
+            self.user_mode = true;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_psr_value(){
+        let mut reg = Register::default();
+        let mut psr = Psr::default();
+        reg[Reg::R_COND] = 0b010;
+        assert_eq!(psr.to_u16(&reg), 0x0002);
+
+        psr.load(&mut reg, 0b1_0000_100_00000_001);
+        assert!(psr.user_mode);
+        assert_eq!(psr.priority, 4);
+        assert_eq!(reg[Reg::R_COND], 0b001);
+        assert_eq!(psr.to_u16(&reg), 0x8401);
+    }
+
+    #[test]
+    fn test_stack_switch(){
+        let mut reg = Register::default();
+        let mut psr = Psr::default();
+        reg[Reg::R_R6] = 0x2FF0;                 // supervisor stack
+        psr.saved_usp = 0xFD00;
+
+        psr.enter_user(&mut reg);
+        assert_eq!(reg[Reg::R_R6], 0xFD00);
+        assert_eq!(psr.saved_ssp, 0x2FF0);
+
+        reg[Reg::R_R6] = 0xFCFE;
+        psr.enter_supervisor(&mut reg);
+        assert_eq!(reg[Reg::R_R6], 0x2FF0);
+        assert_eq!(psr.saved_usp, 0xFCFE);
+
+        psr.enter_supervisor(&mut reg);
+        assert_eq!(reg[Reg::R_R6], 0x2FF0, "no switch when already in supervisor mode");
+    }
+}
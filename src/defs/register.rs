@@ -1,5 +1,7 @@
 use std::ops::{Index, IndexMut};
 
+/// Register names, R0 to R7 are general purpose, PC is the program counter
+/// and COND holds the condition flags of the last result.
 #[derive(PartialEq, Eq, Hash, Copy, Clone)]
 #[allow(non_camel_case_types)]
 pub enum Reg {
@@ -15,16 +17,14 @@ pub enum Reg {
     R_COND = 9,
 }
 
+/// The register file.
+/// implements Index and IndexMut to index with Reg or with a u16 register
+/// number (as decoded from an instruction).
+#[derive(Default)]
 pub struct Register {
     pub reg: [u16; 10],
 }
 
-impl Default for Register {
-    fn default() -> Register {
-        Register { reg: [0; 10] }
-    }
-}
-
 /// override indexing with enum Reg
 impl IndexMut<Reg> for Register {
     fn index_mut(&mut self, index: Reg) -> &mut Self::Output {
@@ -1,4 +1,5 @@
 
+/// Trap vectors, the low byte of a TRAP instruction.
 #[allow(non_camel_case_types)]
 pub enum Traps{
     TRAP_GETC  = 32,    /* get char from keybaord */
@@ -10,14 +11,27 @@ pub enum Traps{
 }
 
 impl Traps {
-    pub fn from_u16(value: u16) -> Self {
+    /// The trap for a vector, None for vectors without a builtin routine.
+    pub fn from_u16(value: u16) -> Option<Self> {
         match value {
-            32 => Self::TRAP_GETC,
-            33 => Self::TRAP_OUT,
-            34 => Self::TRAP_PUTS,
-            35 => Self::TRAP_IN,
-            36 => Self::TRAP_PUTSP,
-            _ => Self::TRAP_HALT,
+            32 => Some(Self::TRAP_GETC),
+            33 => Some(Self::TRAP_OUT),
+            34 => Some(Self::TRAP_PUTS),
+            35 => Some(Self::TRAP_IN),
+            36 => Some(Self::TRAP_PUTSP),
+            37 => Some(Self::TRAP_HALT),
+            _ => None,
         }
     }
 }
+
+/// How TRAP instructions are serviced.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TrapMode {
+    /// the service routines are implemented in Rust, fast and needs no OS.
+    Builtin,
+    /// TRAP saves PC in R7, pushes PSR and PC on the supervisor stack and
+    /// jumps through the trap vector table, the service routines come from
+    /// an OS image loaded in memory, run in supervisor mode and return with RTI.
+    Authentic,
+}
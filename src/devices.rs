@@ -0,0 +1,215 @@
+use std::cell::Cell;
+
+/// Keyboard status register, bit 15 is set while a key is waiting in KBDR,
+/// bit 14 enables the keyboard interrupt.
+pub const MR_KBSR: u16 = 0xFE00;
+/// Keyboard data register, the last key pressed.
+pub const MR_KBDR: u16 = 0xFE02;
+/// Display status register, bit 15 is set when the display can take a character.
+pub const MR_DSR: u16 = 0xFE04;
+/// Display data register, writing it outputs a character.
+pub const MR_DDR: u16 = 0xFE06;
+/// Machine control register, clearing bit 15 stops the clock.
+pub const MR_MCR: u16 = 0xFFFE;
+
+/// Bit 15 of KBSR/DSR, the device is ready.
+pub const STATUS_READY: u16 = 1 << 15;
+/// Bit 14 of KBSR, interrupt when a key is ready.
+pub const KBSR_IE: u16 = 1 << 14;
+/// Bit 15 of MCR, the clock is running.
+pub const MCR_CLOCK: u16 = 1 << 15;
+/// What a program reads instead of a key once the input ran out, when
+/// it's asked to (see EofPolicy).
+pub const KEY_EOF: u16 = 0xFFFF;
+
+/// Device registers of the keyboard, display and machine control.
+///
+/// Memory routes reads and writes of the device addresses here instead
+/// of RAM. Reads and writes are only recorded while the instruction runs,
+/// their side effects (consuming a key, printing a character, stopping
+/// the clock) are applied by the machine once the instruction is done.
+pub struct Devices {
+    pub kbsr: u16,
+    pub kbdr: u16,
+    pub dsr: u16,
+    pub ddr: u16,
+    pub mcr: u16,
+    kbsr_read: Cell<bool>,
+    kbdr_read: Cell<bool>,
+    ddr_written: bool,
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Devices {
+            kbsr: 0,
+            kbdr: 0,
+            dsr: STATUS_READY,
+            ddr: 0,
+            mcr: MCR_CLOCK,
+            kbsr_read: Cell::new(false),
+            kbdr_read: Cell::new(false),
+            ddr_written: false,
+        }
+    }
+}
+
+impl Devices {
+    /// Device register at `address` for reading, None if it isn't one.
+    pub fn register(&self, address: u16) -> Option<&u16> {
+        match address {
+            MR_KBSR => { self.kbsr_read.set(true); Some(&self.kbsr) }
+            MR_KBDR => { self.kbdr_read.set(true); Some(&self.kbdr) }
+            MR_DSR  => Some(&self.dsr),
+            MR_DDR  => Some(&self.ddr),
+            MR_MCR  => Some(&self.mcr),
+            _ => None,
+        }
+    }
+
+    /// Value of the device register at `address` without counting it as a
+    /// read (polling KBSR or consuming the key), None if it isn't one.
+    pub fn peek(&self, address: u16) -> Option<u16> {
+        match address {
+            MR_KBSR => Some(self.kbsr),
+            MR_KBDR => Some(self.kbdr),
+            _ => self.register(address).copied(),
+        }
+    }
+
+    /// Device register at `address` for writing, None if it isn't one.
+    pub fn register_mut(&mut self, address: u16) -> Option<&mut u16> {
+        match address {
+            MR_KBSR => Some(&mut self.kbsr),
+            MR_KBDR => Some(&mut self.kbdr),
+            MR_DSR  => Some(&mut self.dsr),
+            MR_DDR  => { self.ddr_written = true; Some(&mut self.ddr) }
+            MR_MCR  => Some(&mut self.mcr),
+            _ => None,
+        }
+    }
+
+    /// Whether a key is waiting in KBDR.
+    pub fn key_ready(&self) -> bool {
+        self.kbsr & STATUS_READY != 0
+    }
+
+    /// Latch a key into KBDR and flag it ready.
+    pub fn press_key(&mut self, key: u8) {
+        self.kbdr = key as u16;
+        self.kbsr |= STATUS_READY;
+    }
+
+    /// Latch KEY_EOF into KBDR, for programs told the input ran out.
+    pub fn press_eof(&mut self) {
+        self.kbdr = KEY_EOF;
+        self.kbsr |= STATUS_READY;
+    }
+
+    /// Whether the keyboard requests an interrupt, a key is waiting and
+    /// interrupts are enabled.
+    pub fn keyboard_interrupt(&self) -> bool {
+        self.key_ready() && self.kbsr & KBSR_IE != 0
+    }
+
+    /// Whether the last instruction read KBSR.
+    pub fn kbsr_polled(&self) -> bool {
+        self.kbsr_read.get()
+    }
+
+    /// Whether the keyboard should look for a new key: none is waiting and
+    /// the program either polled KBSR or expects an interrupt.
+    pub fn wants_key(&self) -> bool {
+        !self.key_ready() && (self.kbsr_read.get() || self.kbsr & KBSR_IE != 0)
+    }
+
+    /// Hand the waiting key to someone other than the program (GETC), as
+    /// if KBDR was read.
+    pub fn take_key(&mut self) -> Option<u16> {
+        if self.key_ready() {
+            self.kbsr &= !STATUS_READY;
+            Some(self.kbdr)
+        } else {
+            None
+        }
+    }
+
+    /// The character written to DDR, if any, since the last call.
+    pub fn take_output(&mut self) -> Option<u8> {
+        if self.ddr_written {
+            self.ddr_written = false;
+            Some(self.ddr as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Whether MCR still lets the clock run.
+    pub fn clock_running(&self) -> bool {
+        self.mcr & MCR_CLOCK != 0
+    }
+
+    /// Apply the side effects of the last instruction's accesses.
+    /// Reading KBDR consumes the key, the display is always ready and
+    /// the ready bits can't be set by the program.
+    pub fn update(&mut self) {
+        if self.kbdr_read.get() {
+            self.kbsr &= !STATUS_READY;
+        }
+        self.dsr = STATUS_READY;
+        self.kbsr_read.set(false);
+        self.kbdr_read.set(false);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyboard(){
+        let mut devices = Devices::default();
+        assert_eq!(devices.register(MR_KBSR), Some(&0));
+        assert!(devices.wants_key());
+        devices.press_key(b'a');
+        assert!(!devices.wants_key());
+        devices.update();
+
+        assert_eq!(devices.register(MR_KBSR), Some(&STATUS_READY));
+        assert_eq!(devices.register(MR_KBDR), Some(&(b'a' as u16)));
+        devices.update();
+        assert!(!devices.key_ready(), "reading KBDR consumes the key");
+        assert!(!devices.wants_key(), "not polled");
+
+        devices.kbsr |= KBSR_IE;
+        assert!(devices.wants_key(), "interrupts enabled");
+        devices.press_key(b'b');
+        assert_eq!(devices.take_key(), Some(b'b' as u16));
+        assert_eq!(devices.take_key(), None);
+        devices.press_eof();
+        assert_eq!(devices.take_key(), Some(KEY_EOF));
+    }
+
+    #[test]
+    fn test_display(){
+        let mut devices = Devices::default();
+        assert_eq!(devices.take_output(), None);
+        *devices.register_mut(MR_DDR).unwrap() = b'x' as u16;
+        assert_eq!(devices.take_output(), Some(b'x'));
+        assert_eq!(devices.take_output(), None);
+
+        *devices.register_mut(MR_DSR).unwrap() = 0;
+        devices.update();
+        assert_eq!(devices.dsr, STATUS_READY, "display stays ready");
+    }
+
+    #[test]
+    fn test_mcr(){
+        let mut devices = Devices::default();
+        assert!(devices.clock_running());
+        *devices.register_mut(MR_MCR).unwrap() &= 0x7FFF;
+        assert!(!devices.clock_running());
+        assert_eq!(devices.register(0xFE08), None, "unmapped");
+    }
+}
@@ -0,0 +1,148 @@
+use crate::defs::memory::Memory;
+use crate::defs::opcode::Opcode;
+use crate::loader::Image;
+use crate::operations::helper::sign_ext;
+use crate::symbols::SymbolTable;
+use std::fmt;
+
+/// One disassembled word: where it is, what it is and the label placed on it.
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub word: u16,
+    pub label: Option<String>,
+    pub text: String,
+}
+
+/// `x3002  0BFD  LOOP             BRnp LOOP`
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "x{:04X}  {:04X}  {:<16} {}", self.address, self.word,
+            self.label.as_deref().unwrap_or(""), self.text)
+    }
+}
+
+/// Decode the instruction `instr`, found at `address`, into its canonical
+/// mnemonic form (`ADD R1, R2, #3`, `BRnz x3004`, `TRAP x25`).
+///
+/// Fields are extracted the way the operations do it. PC relative targets
+/// are shown as the label placed at that address, as an address otherwise.
+/// Words that aren't instructions (the reserved opcode, a branch that never
+/// branches like x0000) come out as `.FILL`.
+pub fn disassemble(instr: u16, address: u16, symbols: &SymbolTable) -> String {
+    let dr = (instr >> 9) & 0b111;                                      // also SR of the stores
+    let sr1 = (instr >> 6) & 0b111;                                     // also BaseR
+    let pc = address.wrapping_add(1);
+    let target = |bits: i16| {
+        let offset = sign_ext(instr & ((1 << bits) - 1), bits);
+        let address = pc.wrapping_add(offset);
+        match symbols.name(address) {
+            Some(label) => label.to_string(),
+            None => format!("x{:04X}", address),
+        }
+    };
+    let opcode = Opcode::from_u16(instr >> 12);
+    match opcode {
+        Opcode::OP_BR => {
+            let flags = (instr >> 9) & 0b111;
+            if flags == 0 {
+                return fill(instr);
+            }
+            let n = if flags & 0b100 != 0 { "n" } else { "" };
+            let z = if flags & 0b010 != 0 { "z" } else { "" };
+            let p = if flags & 0b001 != 0 { "p" } else { "" };
+            format!("BR{}{}{} {}", n, z, p, target(9))
+        }
+        Opcode::OP_ADD | Opcode::OP_AND => {
+            let name = if matches!(opcode, Opcode::OP_ADD) { "ADD" } else { "AND" };
+            if (instr >> 5) & 0b1 == 1 {
+                let imm5 = sign_ext(instr & 0b11111, 5) as i16;
+                format!("{} R{}, R{}, #{}", name, dr, sr1, imm5)
+            } else {
+                format!("{} R{}, R{}, R{}", name, dr, sr1, instr & 0b111)
+            }
+        }
+        Opcode::OP_NOT => format!("NOT R{}, R{}", dr, sr1),
+        Opcode::OP_LD => format!("LD R{}, {}", dr, target(9)),
+        Opcode::OP_LDI => format!("LDI R{}, {}", dr, target(9)),
+        Opcode::OP_LEA => format!("LEA R{}, {}", dr, target(9)),
+        Opcode::OP_ST => format!("ST R{}, {}", dr, target(9)),
+        Opcode::OP_STI => format!("STI R{}, {}", dr, target(9)),
+        Opcode::OP_LDR | Opcode::OP_STR => {
+            let name = if matches!(opcode, Opcode::OP_LDR) { "LDR" } else { "STR" };
+            let offset6 = sign_ext(instr & 0b111111, 6) as i16;
+            format!("{} R{}, R{}, #{}", name, dr, sr1, offset6)
+        }
+        Opcode::OP_JMP if sr1 == 7 => String::from("RET"),
+        Opcode::OP_JMP => format!("JMP R{}", sr1),
+        Opcode::OP_JSR if (instr >> 11) & 1 == 1 => format!("JSR {}", target(11)),
+        Opcode::OP_JSR => format!("JSRR R{}", sr1),
+        Opcode::OP_TRAP => format!("TRAP x{:02X}", instr & 0xFF),
+        Opcode::OP_RTI => String::from("RTI"),
+        Opcode::OP_RES => fill(instr),
+    }
+}
+
+fn fill(word: u16) -> String {
+    format!(".FILL x{:04X}", word)
+}
+
+/// Disassemble `words` placed from `origin` on.
+pub fn disassemble_words(origin: u16, words: &[u16], symbols: &SymbolTable) -> Vec<Line> {
+    words.iter().enumerate().map(|(i, &word)| {
+        let address = origin.wrapping_add(i as u16);
+        Line {
+            address,
+            word,
+            label: symbols.name(address).map(String::from),
+            text: disassemble(word, address, symbols),
+        }
+    }).collect()
+}
+
+/// Disassemble an object image.
+pub fn disassemble_image(image: &Image, symbols: &SymbolTable) -> Vec<Line> {
+    disassemble_words(image.origin, &image.data, symbols)
+}
+
+/// Disassemble `count` words of memory starting at `start`, device
+/// registers are peeked so nothing is consumed.
+pub fn disassemble_memory(memory: &Memory, start: u16, count: u16, symbols: &SymbolTable) -> Vec<Line> {
+    let words: Vec<u16> = (0..count).map(|i| memory.peek(start.wrapping_add(i))).collect();
+    disassemble_words(start, &words, symbols)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_disassemble(){
+        let none = SymbolTable::new();
+        assert_eq!(disassemble(0b0001_001_010_1_00011, 0x3000, &none), "ADD R1, R2, #3");
+        assert_eq!(disassemble(0b0101_000_000_1_10000, 0x3000, &none), "AND R0, R0, #-16");
+        assert_eq!(disassemble(0b0000_110_000000011, 0x3000, &none), "BRnz x3004");
+        assert_eq!(disassemble(0xF025, 0x3000, &none), "TRAP x25");
+        assert_eq!(disassemble(0xC1C0, 0x3000, &none), "RET");
+        assert_eq!(disassemble(0x6D83, 0x3000, &none), "LDR R6, R6, #3");
+        assert_eq!(disassemble(0x0000, 0x3000, &none), ".FILL x0000");
+        assert_eq!(disassemble(0xD123, 0x3000, &none), ".FILL xD123");
+        let symbols = SymbolTable::from_pairs(&[(String::from("LOOP"), 0x2FFE)]);
+        assert_eq!(disassemble(0x4FFD, 0x3000, &symbols), "JSR LOOP");
+    }
+
+    #[test]
+    fn test_disassemble_image(){
+        let source = std::fs::read_to_string("lc3os.asm").unwrap();
+        let os = assemble(&source).unwrap();
+        let symbols = os.symbol_table();
+        let lines = disassemble_image(&os.image(), &symbols);
+        // data reads as whatever instruction it happens to encode
+        assert_eq!(lines[0x25].to_string(), "x0025  0241                   BRp x0067");
+        let halt = lines.iter().position(|line| line.label.as_deref() == Some("TRAP_HALT")).unwrap();
+        assert_eq!(lines[halt].to_string(), "x0241  302B  TRAP_HALT        ST R0, HALT_SAVE_R0");
+        assert!(lines[halt..].iter().any(|line| line.text == "RTI"));
+    }
+}
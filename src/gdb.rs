@@ -0,0 +1,590 @@
+//! A GDB Remote Serial Protocol stub, so debugger front ends that speak
+//! it can drive a [`Vm`] over TCP or a Unix socket.
+//!
+//! Registers are numbered R0-R7 (0-7), PC (8), COND (9) and PSR (10), each
+//! 16 bits, the target description gdb asks for (`qXfer:features:read`)
+//! says so. The LC3's addressable unit is the 16 bit word and the stub
+//! counts in words everywhere: addresses in memory, breakpoint, watchpoint
+//! and stop packets are word addresses like PC and R7, lengths count words.
+//! Words and registers go over the wire big-endian, `m3000,2` answers
+//! `12345678` for x1234 at x3000 and x5678 at x3001.
+//!
+//! Supported: `?`, `g`/`G`, `p`/`P`, `m`/`M`, `s`, `c` (interrupted by
+//! Ctrl-C), `Z0`/`Z1` breakpoints, `Z2`-`Z4` watchpoints, `qSupported`,
+//! `QStartNoAckMode`, `D` and `k`. Anything else gets the empty reply
+//! meaning "not supported".
+
+use crate::defs::error::VmError;
+use crate::defs::memory::{Access, MEMORY_SIZE};
+use crate::defs::register::Reg;
+use crate::vm::{StopReason, Vm};
+use crate::watch::{WatchKind, Watchpoint};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// How many instructions `c` runs between two looks for a Ctrl-C.
+const INTERRUPT_INTERVAL: u64 = 4096;
+
+/// R0-R7, PC, COND and PSR.
+const REGISTERS: u16 = 11;
+
+/// The largest packet gdb may send and the stub replies with, in characters.
+const PACKET_SIZE: usize = 0x1000;
+
+/// The most words an `m` reply can hold, four hex digits each.
+const MAX_READ: usize = PACKET_SIZE / 4;
+
+/// What gdb sends to interrupt a running target.
+const INTERRUPT: u8 = 0x03;
+
+/// The registers as gdb should see them.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int"/>
+    <reg name="r2" bitsize="16" type="int"/>
+    <reg name="r3" bitsize="16" type="int"/>
+    <reg name="r4" bitsize="16" type="int"/>
+    <reg name="r5" bitsize="16" type="int"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="cond" bitsize="16" type="int"/>
+    <reg name="psr" bitsize="16" type="int"/>
+  </feature>
+</target>
+"#;
+
+/// A connection to gdb.
+pub trait Connection: Read + Write {
+    /// Whether gdb asked to interrupt the target (sent x03), without
+    /// waiting for it to. Anything else it sent meanwhile is dropped.
+    fn interrupted(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let result = read_waiting(self);
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let result = read_waiting(self);
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+/// Whether the bytes a non-blocking `stream` has waiting include an interrupt.
+fn read_waiting(stream: &mut dyn Read) -> io::Result<bool> {
+    let mut buffer = [0; 64];
+    match stream.read(&mut buffer) {
+        Ok(n) => Ok(buffer[..n].contains(&INTERRUPT)),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// What came over the connection.
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+pub struct GdbStub {
+    pub vm: Vm,
+    acks: bool,                                                         // until QStartNoAckMode
+}
+
+impl GdbStub {
+    pub fn new(vm: Vm) -> Self {
+        GdbStub { vm, acks: true }
+    }
+
+    /// Answer gdb on `connection` until it detaches, kills the target or
+    /// hangs up.
+    pub fn serve(&mut self, connection: &mut dyn Connection) -> io::Result<()> {
+        loop {
+            let packet = match self.receive(connection)? {
+                None => return Ok(()),
+                Some(Incoming::Interrupt) => {
+                    self.send(connection, "S02")?;                      // nothing is running
+                    continue;
+                }
+                Some(Incoming::Packet(packet)) => packet,
+            };
+            match packet.as_str() {
+                "D" => return self.send(connection, "OK"),
+                "k" => return Ok(()),
+                _ => {
+                    let reply = self.reply(&packet, connection)?;
+                    self.send(connection, &reply)?;
+                }
+            }
+        }
+    }
+
+    /// The reply to `packet`, `connection` is watched for a Ctrl-C while
+    /// the machine runs.
+    fn reply(&mut self, packet: &str, connection: &mut dyn Connection) -> io::Result<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => String::from("S05"),
+            "g" => (0..REGISTERS).map(|r| format!("{:04x}", self.register(r))).collect(),
+            "G" => match hex_words(args) {
+                Some(values) if values.len() == REGISTERS as usize => {
+                    for (r, value) in values.into_iter().enumerate() {
+                        self.set_register(r as u16, value);
+                    }
+                    String::from("OK")
+                }
+                _ => String::from("E01"),
+            },
+            "p" => match u16::from_str_radix(args, 16) {
+                Ok(r) if r < REGISTERS => format!("{:04x}", self.register(r)),
+                _ => String::from("E01"),
+            },
+            "P" => match args.split_once('=').map(|(r, value)| (u16::from_str_radix(r, 16), hex_words(value))) {
+                Some((Ok(r), Some(value))) if r < REGISTERS && value.len() == 1 => {
+                    self.set_register(r, value[0]);
+                    String::from("OK")
+                }
+                _ => String::from("E01"),
+            },
+            "m" => match address_length(args).filter(|(_, length)| *length <= MAX_READ)
+                .and_then(|(address, length)| Some((memory_range(address, length)?, length))) {
+                Some((address, length)) => self.read_memory(address, length),
+                None => String::from("E01"),
+            },
+            "M" => match args.split_once(':').and_then(|(at, data)| Some((address_length(at)?, hex_words(data)?))) {
+                Some(((address, length), words)) if words.len() == length => match memory_range(address, length) {
+                    Some(address) => {
+                        self.write_memory(address, &words);
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                },
+                _ => String::from("E01"),
+            },
+            "s" | "c" => {
+                if !args.is_empty() {
+                    match usize::from_str_radix(args, 16).ok().and_then(|pc| memory_range(pc, 1)) {
+                        Some(pc) => self.vm.reg[Reg::R_PC] = pc,
+                        None => return Ok(String::from("E01")),
+                    }
+                }
+                if command == "s" {
+                    let stop = self.vm.step();
+                    self.stop_reply(stop)
+                } else {
+                    self.resume(connection)?
+                }
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "H" => String::from("OK"),                                  // a single thread
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE);
+        }
+        if packet == "QStartNoAckMode" {
+            self.acks = false;
+            return String::from("OK");
+        }
+        if packet == "qAttached" {
+            return String::from("1");
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match address_length(rest) {
+                Some((offset, length)) => {
+                    let data = TARGET_XML.get(offset..).unwrap_or_default();
+                    if data.len() > length {
+                        format!("m{}", &data[..length])
+                    } else {
+                        format!("l{}", data)
+                    }
+                }
+                None => String::from("E01"),
+            };
+        }
+        String::new()
+    }
+
+    /// Run until something stops the machine or gdb interrupts it.
+    fn resume(&mut self, connection: &mut dyn Connection) -> io::Result<String> {
+        loop {
+            match self.vm.run_for(INTERRUPT_INTERVAL) {
+                StopReason::BudgetExhausted => {
+                    if connection.interrupted()? {
+                        return Ok(String::from("S02"));
+                    }
+                }
+                stop => return Ok(self.stop_reply(Some(stop))),
+            }
+        }
+    }
+
+    /// The stop reply for `stop`: a halted program exited, faults are the
+    /// signals a process would get and anything else is a SIGTRAP.
+    fn stop_reply(&self, stop: Option<StopReason>) -> String {
+        match stop {
+            _ if !self.vm.running => String::from("W00"),
+            Some(StopReason::Fault(VmError::AccessViolation(_))) => String::from("S0b"),
+            Some(StopReason::Fault(VmError::ReservedInstruction(_)))
+            | Some(StopReason::Fault(VmError::PrivilegeViolation)) => String::from("S04"),
+            Some(StopReason::Timeout) => String::from("S0e"),
+            Some(StopReason::Watchpoint(index, Some(Access { address, .. }))) => {
+                let kind = match self.vm.watchpoints[index] {
+                    Watchpoint::Memory { kind: WatchKind::Read, .. } => "rwatch",
+                    Watchpoint::Memory { kind: WatchKind::Access, .. } => "awatch",
+                    _ => "watch",
+                };
+                format!("T05{}:{:x};", kind, address)
+            }
+            _ => String::from("S05"),
+        }
+    }
+
+    /// `Z0,addr,kind` sets a breakpoint (Z1 too, they're all software ones),
+    /// `Z2,addr,length` a write watchpoint on `length` words, Z3 a read and
+    /// Z4 an access one. `z` removes them.
+    fn breakpoint(&mut self, set: bool, args: &str) -> String {
+        let mut fields = args.splitn(3, ',');
+        let (kind, address, length) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(address), Some(length)) => (kind, address, length),
+            _ => return String::from("E01"),
+        };
+        let (address, length) = match (usize::from_str_radix(address, 16), usize::from_str_radix(length, 16)) {
+            (Ok(address), Ok(length)) => (address, length),
+            _ => return String::from("E01"),
+        };
+        let words = if matches!(kind, "0" | "1") { 1 } else { length.max(1) };
+        let address = match memory_range(address, words) {
+            Some(address) => address,
+            None => return String::from("E01"),
+        };
+        let kind = match kind {
+            "0" | "1" => {
+                if set {
+                    self.vm.breakpoints.insert(address);
+                } else {
+                    self.vm.breakpoints.remove(&address);
+                }
+                return String::from("OK");
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let end = address + (words - 1) as u16;
+        let watchpoint = Watchpoint::Memory { start: address, end, kind, condition: None };
+        if set {
+            self.vm.watchpoints.push(watchpoint);
+        } else {
+            self.vm.watchpoints.retain(|w| *w != watchpoint);
+        }
+        String::from("OK")
+    }
+
+    fn register(&self, r: u16) -> u16 {
+        match r {
+            10 => self.vm.psr.to_u16(&self.vm.reg),
+            r => self.vm.reg[r],
+        }
+    }
+
+    fn set_register(&mut self, r: u16, value: u16) {
+        match r {
+            9 => self.vm.reg[Reg::R_COND] = value & 0b111,
+            10 => self.vm.psr.load(&mut self.vm.reg, value),
+            r => self.vm.reg[r] = value,
+        }
+    }
+
+    /// `length` words from `address` on, as hex.
+    fn read_memory(&self, address: u16, length: usize) -> String {
+        (0..length)
+            .map(|i| format!("{:04x}", self.vm.memory.peek(address.wrapping_add(i as u16))))
+            .collect()
+    }
+
+    /// Write `words` from `address` on.
+    fn write_memory(&mut self, address: u16, words: &[u16]) {
+        for (i, word) in words.iter().enumerate() {
+            self.vm.memory[address.wrapping_add(i as u16)] = *word;
+        }
+    }
+
+    /// The next packet or interrupt, acknowledging packets while acks are
+    /// on. None once gdb hangs up.
+    fn receive(&mut self, connection: &mut dyn Connection) -> io::Result<Option<Incoming>> {
+        loop {
+            match read_byte(connection)? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(Incoming::Interrupt)),
+                Some(b'$') => {}
+                Some(_) => continue,                                    // acks and noise
+            }
+            let mut data = Vec::new();
+            loop {
+                match read_byte(connection)? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                *digit = match read_byte(connection)? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+            let valid = std::str::from_utf8(&checksum).ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                .is_some_and(|checksum| checksum == sum(&data));
+            if self.acks {
+                connection.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(Incoming::Packet(String::from_utf8_lossy(&unescape(&data)).into_owned())));
+            }
+        }
+    }
+
+    fn send(&mut self, connection: &mut dyn Connection, reply: &str) -> io::Result<()> {
+        let data = escape(reply.as_bytes());
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend(&data);
+        packet.extend(format!("#{:02x}", sum(&data)).bytes());
+        connection.write_all(&packet)?;
+        connection.flush()
+    }
+}
+
+fn read_byte(connection: &mut dyn Connection) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match connection.read(&mut byte) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(byte[0])),
+        Err(e) if e.kind() == ErrorKind::Interrupted => read_byte(connection),
+        Err(e) => Err(e),
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// `$`, `#`, `}` and `*` are sent as `}` and the byte xor x20.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.extend([b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = data.iter();
+    let mut unescaped = Vec::with_capacity(data.len());
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            byte => unescaped.push(byte),
+        }
+    }
+    unescaped
+}
+
+/// `3000,4` as an address and a length.
+fn address_length(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+/// `address` as a word address, None unless the `length` words from there
+/// are all in memory.
+fn memory_range(address: usize, length: usize) -> Option<u16> {
+    if address.checked_add(length)? <= MEMORY_SIZE { Some(address as u16) } else { None }
+}
+
+fn hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+/// Big-endian words, the format of register values.
+fn hex_words(text: &str) -> Option<Vec<u16>> {
+    let bytes = hex_bytes(text)?;
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+
+    /// gdb's side of the connection, everything it sends is queued up front.
+    struct Session {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Session {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Session {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Session {
+        fn interrupted(&mut self) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, sum(data.as_bytes()))
+    }
+
+    /// The replies of a stub to `packets`, acks left out.
+    fn session(packets: &[&str]) -> Vec<String> {
+        let program = assemble("
+            .ORIG x3000
+            AND R0, R0, #0
+    LOOP    ADD R0, R0, #1
+            ADD R1, R0, #-3
+            BRn LOOP
+            ST R0, RESULT
+            HALT
+    RESULT  .FILL 0
+            .END").unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.load_images(&[program.image()], 0).unwrap();
+        let mut stub = GdbStub::new(vm);
+        let input: String = packets.iter().map(|data| packet(data)).collect();
+        let mut connection = Session { input: io::Cursor::new(input.into_bytes()), output: Vec::new() };
+        stub.serve(&mut connection).unwrap();
+        let output = String::from_utf8(connection.output).unwrap();
+        output.split('$').skip(1).map(|reply| {
+            let (data, checksum) = reply.split_once('#').unwrap();
+            assert_eq!(&checksum[..2], format!("{:02x}", sum(data.as_bytes())), "checksum of {}", data);
+            data.to_string()
+        }).collect()
+    }
+
+    #[test]
+    fn test_registers_and_memory(){
+        let replies = session(&["?", "g", "P1=1234", "p1", "pa", "pb", "m3000,2", "M4000,2:abcdef01", "m4000,2",
+            "G0001000200030004000500060007000830020002040f", "g", "M4000,2:abcd", "m10000,1", "mffff,2", "Mffff,2:00010002",
+            "m0,401", "m0,ffffffffffff", "Z2,fffe,4", "mffff,1"]);
+        assert_eq!(replies, vec![
+            "S05",
+            "00000000000000000000000030000000300000020002",
+            "OK",
+            "1234",
+            "0002",
+            "E01",
+            "50201021",
+            "OK",
+            "abcdef01",
+            "OK",
+            "00010002000300040005000600070008300200070407",
+            "E01",
+            "E01",
+            "E01",
+            "E01",
+            "E01",
+            "E01",
+            "E01",
+            "0000",
+        ]);
+
+        // gdb splits a long read, the second packet picks up where the first stopped
+        let whole = session(&["m3000,4"]);
+        let split = session(&["m3000,2", "m3002,2"]);
+        assert_eq!(whole[0], "50201021123d09fd");
+        assert_eq!(whole[0], split.concat());
+    }
+
+    #[test]
+    fn test_execution(){
+        let replies = session(&["s", "p8", "m3001,1", "Z0,3002,1", "c", "p0", "c", "z0,3002,1", "Z2,3006,1", "c", "p8", "c", "s", "D"]);
+        assert_eq!(replies, vec![
+            "S05",
+            "3001",
+            "1021",
+            "OK",
+            "S05",
+            "0001",
+            "S05",
+            "OK",
+            "OK",
+            "T05watch:3006;",
+            "3005",
+            "W00",
+            "W00",
+            "OK",
+        ]);
+    }
+
+    #[test]
+    fn test_protocol(){
+        let replies = session(&["qSupported:multiprocess+;xmlRegisters=i386", "Hg0", "vMustReplyEmpty", "qXfer:features:read:target.xml:0,10",
+            "qXfer:features:read:target.xml:10,1000"]);
+        assert_eq!(replies[0], "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+");
+        assert_eq!(replies[1], "OK");
+        assert_eq!(replies[2], "", "not supported");
+        assert_eq!(replies[3], "m<?xml version=\"1");
+        assert_eq!(replies[4], format!("l{}", &TARGET_XML[16..]), "the rest, and the last of it");
+
+        // a corrupted packet is nacked and dropped, acks stop once asked to
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.reg[Reg::R_R0] = 0x41;
+        let mut stub = GdbStub::new(vm);
+        let input = format!("$p0#00{}{}{}", packet("p0"), packet("QStartNoAckMode"), packet("p0"));
+        let mut connection = Session { input: io::Cursor::new(input.into_bytes()), output: Vec::new() };
+        stub.serve(&mut connection).unwrap();
+        assert_eq!(String::from_utf8(connection.output).unwrap(), "-+$0041#c5+$OK#9a$0041#c5");
+        assert_eq!(escape(b"a}b#"), b"a}]b}\x03");
+        assert_eq!(unescape(b"a}]b}\x03"), b"a}b#");
+    }
+}
@@ -0,0 +1,119 @@
+use crate::defs::error::VmError;
+use crate::defs::memory::Memory;
+use crate::defs::psr::Psr;
+use crate::defs::register::*;
+use crate::devices::Devices;
+
+/// Base of the interrupt vector table, vectors x00-xFF live at x0100-x01FF.
+pub const INT_VECTOR_TABLE: u16 = 0x0100;
+/// Privilege mode violation exception vector.
+pub const PRIVILEGE_VECTOR: u8 = 0x00;
+/// Illegal opcode exception vector.
+pub const ILLEGAL_OPCODE_VECTOR: u8 = 0x01;
+/// Access control violation exception vector.
+pub const ACV_VECTOR: u8 = 0x02;
+/// Keyboard interrupt vector.
+pub const KBD_VECTOR: u8 = 0x80;
+/// Keyboard interrupt priority.
+pub const KBD_PRIORITY: u16 = 4;
+
+/// A request to run a service routine, `vector` indexes the vector table.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Interrupt {
+    pub vector: u8,
+    pub priority: u16,
+}
+
+/// The interrupt the processor should take now, if any.
+///
+/// A device interrupts when it's ready with its interrupt enable bit set,
+/// the request is only accepted if its priority is higher than the one the
+/// processor is running at.
+pub fn pending(devices: &Devices, psr: &Psr) -> Option<Interrupt> {
+    if devices.keyboard_interrupt() && KBD_PRIORITY > psr.priority {
+        return Some(Interrupt { vector: KBD_VECTOR, priority: KBD_PRIORITY });
+    }
+    None
+}
+
+/// The exception vector an error is raised through, None for errors that
+/// aren't LC3 exceptions (running out of input, failing I/O).
+pub fn exception_vector(error: &VmError) -> Option<u8> {
+    match error {
+        VmError::PrivilegeViolation => Some(PRIVILEGE_VECTOR),
+        VmError::ReservedInstruction(_) => Some(ILLEGAL_OPCODE_VECTOR),
+        VmError::AccessViolation(_) => Some(ACV_VECTOR),
+        _ => None,
+    }
+}
+
+/// Enter a service routine.
+///
+/// The PSR and PC are pushed on the supervisor stack (switching to it first
+/// when running in user mode), the priority is raised to the interrupt's
+/// and PC is loaded from the vector table. RTI undoes all of this.
+/// Exceptions go through here too, at the priority already running.
+pub fn interrupt(reg: &mut Register, psr: &mut Psr, memory: &mut Memory, int: Interrupt) {
+    save_state(reg, psr, memory);
+    psr.priority = int.priority;
+    reg[Reg::R_PC] = memory[INT_VECTOR_TABLE + int.vector as u16];
+}
+
+/// Enter a trap service routine in authentic mode, like an interrupt
+/// service routine but through the trap vector table and at the priority
+/// already running. The routine returns with RTI, so a TRAP made in user
+/// mode runs its routine in supervisor mode.
+pub fn trap(reg: &mut Register, psr: &mut Psr, memory: &mut Memory, vector: u8) {
+    save_state(reg, psr, memory);
+    reg[Reg::R_PC] = memory.read_system(vector as u16);
+}
+
+/// Switch to supervisor mode and push PSR and PC on the supervisor stack.
+fn save_state(reg: &mut Register, psr: &mut Psr, memory: &mut Memory) {
+    let saved_psr = psr.to_u16(reg);
+    psr.enter_supervisor(reg);
+    let sp = reg[Reg::R_R6].wrapping_sub(1);
+    memory[sp] = saved_psr;                                     // push PSR
+    let sp = sp.wrapping_sub(1);
+    memory[sp] = reg[Reg::R_PC];                                // push PC
+    reg[Reg::R_R6] = sp;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::KBSR_IE;
+
+    #[test]
+    fn test_pending(){
+        let mut devices = Devices::default();
+        let mut psr = Psr::default();
+        devices.press_key(b'a');
+        assert_eq!(pending(&devices, &psr), None, "interrupts disabled");
+        devices.kbsr |= KBSR_IE;
+        assert_eq!(pending(&devices, &psr), Some(Interrupt { vector: 0x80, priority: 4 }));
+        psr.priority = 4;
+        assert_eq!(pending(&devices, &psr), None, "already running at priority 4");
+    }
+
+    #[test]
+    fn test_interrupt(){
+        let mut reg = Register::default();
+        let mut psr = Psr { user_mode: true, saved_ssp: 0x3000, ..Psr::default() };
+        let mut memory = Memory::new();
+        memory[0x0180] = 0x1000;
+        reg[Reg::R_PC] = 0x3004;
+        reg[Reg::R_R6] = 0xF000;
+        reg[Reg::R_COND] = 0b010;
+
+        interrupt(&mut reg, &mut psr, &mut memory, Interrupt { vector: 0x80, priority: 4 });
+        assert_eq!(reg[Reg::R_PC], 0x1000);
+        assert_eq!(reg[Reg::R_R6], 0x2FFE);
+        assert_eq!(memory[0x2FFF], 0x8002, "user PSR pushed");
+        assert_eq!(memory[0x2FFE], 0x3004, "PC pushed");
+        assert!(!psr.user_mode);
+        assert_eq!(psr.priority, 4);
+        assert_eq!(psr.saved_usp, 0xF000);
+    }
+}
@@ -0,0 +1,71 @@
+//! Virtual machine implementing LC3 (Little Computer - 3).
+//!
+//! The crate is split the same way the machine is:
+//!
+//! * [`defs`] holds the machine definitions: memory, registers, opcodes,
+//!   trap vectors and condition flags.
+//! * [`console`] is where input comes from and output goes, the trap
+//!   routines and devices only do I/O through a [`Console`].
+//! * [`devices`] models the memory mapped keyboard, display and machine
+//!   control registers.
+//! * [`interrupts`] raises device interrupts through the vector table.
+//! * [`operations`] implements every instruction, [`execute`] decodes an
+//!   instruction and dispatches it to the right operation.
+//! * [`loader`] reads object images and places them in memory.
+//! * [`assembler`] turns LC3 assembly into object images or relocatable
+//!   [`object`] modules, the [`linker`] combines modules into an image.
+//! * [`disassembler`] turns words back into assembly.
+//! * [`symbols`] maps addresses to labels so they can be shown as `LOOP+3`.
+//! * [`terminal`] switches the terminal to raw mode for interactive programs.
+//! * [`vm`] ties them together in a [`Vm`] that can be stepped or run.
+//! * [`debugger`] steps a [`Vm`] interactively, [`watch`] has the
+//!   watchpoints and conditions it can stop on.
+//! * [`gdb`] lets gdb (or another front end speaking its remote protocol)
+//!   drive a [`Vm`].
+//!
+//! Running a program looks like this:
+//!
+//! ```no_run
+//! use virtual_machine::*;
+//!
+//! let mut vm = Vm::new();
+//! vm.load_images(&[Image::from_file("halt.obj").unwrap()], 0).unwrap();
+//! assert_eq!(vm.run(), StopReason::Halted);
+//! ```
+#![allow(clippy::unusual_byte_groupings)]
+
+pub mod assembler;
+pub mod console;
+pub mod debugger;
+pub mod defs;
+pub mod devices;
+pub mod disassembler;
+pub mod gdb;
+pub mod interrupts;
+pub mod linker;
+pub mod loader;
+pub mod object;
+pub mod operations;
+pub mod symbols;
+pub mod terminal;
+pub mod vm;
+pub mod watch;
+
+pub use assembler::{assemble, assemble_in, Assembly, Diagnostic};
+pub use console::{BufferConsole, Console, Input, RedirectedConsole, ScriptedConsole, StdConsole};
+pub use debugger::Debugger;
+pub use defs::error::VmError;
+pub use defs::memory::{Access, AccessKind, Memory, MEMORY_SIZE};
+pub use defs::psr::Psr;
+pub use defs::register::{Reg, Register};
+pub use defs::traps::TrapMode;
+pub use disassembler::{disassemble, disassemble_image, disassemble_memory};
+pub use gdb::{Connection, GdbStub};
+pub use linker::{link, LinkError};
+pub use loader::{find_overlaps, load_image, load_images, read_image_file, read_symbols_for, Image, Overlap, LC3OS};
+pub use object::{Object, Relocation, RelocationKind};
+pub use operations::executor::execute;
+pub use symbols::{parse_number, SymbolTable};
+pub use terminal::RawTerminal;
+pub use vm::{EofPolicy, StopReason, Vm};
+pub use watch::{Condition, WatchKind, Watchpoint};
@@ -0,0 +1,190 @@
+//! Combining object modules into one loadable image.
+//!
+//! Modules are placed one after the other, the first at `origin` (its own
+//! .ORIG unless another one is given). Offsets between labels of the same
+//! module don't change when it moves, only .FILLs of its labels and the
+//! references to imported labels are patched, and a PC offset that doesn't
+//! reach its label once everything is placed is an error.
+
+use crate::loader::Image;
+use crate::object::{Object, RelocationKind};
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A problem linking a module, `module` is the name it was given to [`link`].
+#[derive(Debug, PartialEq)]
+pub struct LinkError {
+    pub module: String,
+    pub message: String,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.module, self.message)
+    }
+}
+
+/// Link `modules` (a name for the messages and the module) into an image
+/// starting at `origin`, or at the origin of the first module. The symbols
+/// of every module come along at their final addresses, a label defined in
+/// several modules is shown as the first one.
+///
+/// Every problem is reported, nothing is produced if there's any.
+pub fn link(modules: &[(String, Object)], origin: Option<u16>) -> Result<(Image, SymbolTable), Vec<LinkError>> {
+    let mut errors = Vec::new();
+    let error = |module: &str, message: String| LinkError { module: module.to_string(), message };
+    let origin = match (origin, modules.first()) {
+        (Some(origin), _) => origin,
+        (None, Some((_, first))) => first.origin,
+        (None, None) => return Err(vec![error("link", String::from("no modules to link"))]),
+    };
+
+    // where each module goes
+    let mut bases = Vec::new();
+    let mut end = origin as usize;
+    for (name, object) in modules {
+        bases.push(end as u16);
+        end += object.words.len();
+        if end > 0x10000 {
+            return Err(vec![error(name, format!("runs past the end of memory when linked at x{:04X}", origin))]);
+        }
+    }
+
+    // what each module exports, at its final address
+    let mut exports: HashMap<&str, (u16, &str)> = HashMap::new();
+    for ((name, object), base) in modules.iter().zip(&bases) {
+        for export in &object.exports {
+            let address = match object.symbols.iter().find(|(label, _)| label == export) {
+                Some((_, address)) => address.wrapping_sub(object.origin).wrapping_add(*base),
+                None => {
+                    errors.push(error(name, format!("exports {} but doesn't define it", export)));
+                    continue;
+                }
+            };
+            match exports.get(export.as_str()) {
+                Some((_, first)) => errors.push(error(name, format!("{} is also exported by {}", export, first))),
+                None => {
+                    exports.insert(export, (address, name));
+                }
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(end - origin as usize);
+    let mut symbols = SymbolTable::new();
+    for ((name, object), &base) in modules.iter().zip(&bases) {
+        let mut words = object.words.clone();
+        for relocation in &object.relocations {
+            let offset = relocation.offset as usize;
+            let pc = base.wrapping_add(relocation.offset);
+            let target = match &relocation.symbol {
+                Some(symbol) => match exports.get(symbol.as_str()) {
+                    Some((address, _)) => *address,
+                    None => {
+                        errors.push(error(name, format!("x{:04X}: {} is imported but no module exports it", pc, symbol)));
+                        continue;
+                    }
+                },
+                None => {
+                    // a label of the module, only addresses move with it
+                    if relocation.kind == RelocationKind::Fill {
+                        words[offset] = words[offset].wrapping_sub(object.origin).wrapping_add(base);
+                    }
+                    continue;
+                }
+            };
+            let (field, bits) = match relocation.kind {
+                RelocationKind::Fill => {
+                    words[offset] = target;
+                    continue;
+                }
+                RelocationKind::PcOffset9 => ("PCoffset9", 9),
+                RelocationKind::PcOffset11 => ("PCoffset11", 11),
+            };
+            let distance = target as i32 - (pc as i32 + 1);
+            let (min, max) = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1);
+            if !(min..=max).contains(&distance) {
+                let over = if distance > max { distance - max } else { min - distance };
+                let unit = if over == 1 { "word" } else { "words" };
+                errors.push(error(name, format!("x{:04X}: {} to {} out of range by {} {}, it's at x{:04X}",
+                    pc, field, relocation.symbol.as_ref().unwrap(), over, unit, target)));
+                continue;
+            }
+            let mask = (1u16 << bits) - 1;
+            words[offset] = (words[offset] & !mask) | (distance as u16 & mask);
+        }
+        data.extend(words);
+        for (label, address) in &object.symbols {
+            if symbols.address(label).is_none() {
+                symbols.insert(label, address.wrapping_sub(object.origin).wrapping_add(base));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok((Image { origin, data }, symbols))
+    } else {
+        Err(errors)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn module(name: &str, source: &str) -> (String, Object) {
+        (name.to_string(), assemble(source).unwrap().object())
+    }
+
+    #[test]
+    fn test_link(){
+        let main = module("main.o", "
+            .ORIG x3000
+            .IMPORT PRINT
+            .EXPORT MAIN
+    MAIN    JSR PRINT
+            LD R0, PTR
+            HALT
+    PTR     .FILL MAIN
+            .END");
+        let print = module("print.o", "
+            .ORIG x4000
+            .EXPORT PRINT
+    PRINT   PUTS
+            RET
+            .END");
+        let (image, symbols) = link(&[main.clone(), print.clone()], None).unwrap();
+        assert_eq!(image.origin, 0x3000);
+        assert_eq!(image.data, vec![0x4803, 0x2001, 0xF025, 0x3000, 0xF022, 0xC1C0]);
+        assert_eq!(symbols.address("PRINT"), Some(0x3004));
+
+        // moved, the .FILL follows its label
+        let (image, _) = link(&[print.clone(), main.clone()], Some(0x5000)).unwrap();
+        assert_eq!(image.data, vec![0xF022, 0xC1C0, 0x4FFD, 0x2001, 0xF025, 0x5002]);
+
+        let errors = link(std::slice::from_ref(&main), None).unwrap_err();
+        assert_eq!(errors[0].to_string(), "main.o: x3000: PRINT is imported but no module exports it");
+    }
+
+    #[test]
+    fn test_out_of_range(){
+        let near = module("near.o", "
+            .ORIG x3000
+            .IMPORT FAR
+            LD R0, FAR
+            .END");
+        let filler = module("filler.o", ".ORIG x3000\n.BLKW 300\n.END");
+        let far = module("far.o", "
+            .ORIG x3000
+            .EXPORT FAR
+    FAR     .FILL 0
+            .END");
+        let errors = link(&[near, filler, far], None).unwrap_err();
+        assert_eq!(errors, vec![LinkError {
+            module: String::from("near.o"),
+            message: String::from("x3000: PCoffset9 to FAR out of range by 45 words, it's at x312D"),
+        }]);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::defs::memory::{Memory, MEMORY_SIZE};
+use crate::symbols::SymbolTable;
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+
+/// The bundled LC3 operating system (lc3os.asm), trap and interrupt vector
+/// tables plus the trap service routines, for running traps in authentic mode.
+pub const LC3OS: &[u8] = include_bytes!("../lc3os.obj");
+
+/// An LC3 object image, the origin word followed by the words that are
+/// placed in memory starting at that origin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub origin: u16,
+    pub data: Vec<u16>,
+}
+
+impl Image {
+    /// Build an image from its words, the first one being the origin.
+    pub fn from_words(words: &[u16]) -> Result<Self, Error> {
+        match words.split_first() {
+            Some((origin, data)) => Ok(Image { origin: *origin, data: data.to_vec() }),
+            None => Err(Error::new(ErrorKind::InvalidData,
+                "image is missing its origin word")),
+        }
+    }
+
+    /// Build an image from the bytes of an object file.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        Self::from_words(&get_instr_from_buffer(data)?)
+    }
+
+    /// Read an object file from disk.
+    pub fn from_file(image_path: &str) -> Result<Self, Error> {
+        let mut buffer = Vec::new();
+        File::open(image_path)?.read_to_end(&mut buffer)?;
+        Self::from_bytes(&buffer)
+    }
+
+    /// The bundled operating system image.
+    pub fn lc3os() -> Self {
+        Self::from_bytes(LC3OS).expect("bundled lc3os.obj is a valid image")
+    }
+
+    /// The bytes of the object file holding the image, big-endian words.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        std::iter::once(self.origin)
+            .chain(self.data.iter().copied())
+            .flat_map(|word| word.to_be_bytes())
+            .collect()
+    }
+
+    /// One past the last address occupied by the image.
+    pub fn end(&self) -> usize {
+        self.origin as usize + self.data.len()
+    }
+}
+
+/// Two images that claim the same addresses. `first` and `second` are the
+/// positions of the images in the list given to the loader, `start` and
+/// `end` the (inclusive) range they share.
+#[derive(Debug, PartialEq)]
+pub struct Overlap {
+    pub first: usize,
+    pub second: usize,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "image {} overlaps image {} at x{:04X}-x{:04X}",
+            self.first, self.second, self.start, self.end)
+    }
+}
+
+/// Find every pair of images that share at least one address.
+pub fn find_overlaps(images: &[Image]) -> Vec<Overlap> {
+    let mut overlaps = Vec::new();
+    for (i, a) in images.iter().enumerate() {
+        for (j, b) in images.iter().enumerate().skip(i + 1) {
+            let start = a.origin.max(b.origin) as usize;
+            let end = a.end().min(b.end());
+            if start < end {
+                overlaps.push(Overlap { first: i, second: j, start: start as u16, end: (end - 1) as u16 });
+            }
+        }
+    }
+    overlaps
+}
+
+/// Load several images into one address space.
+///
+/// Nothing is written if an image doesn't fit in memory or if any two images
+/// overlap, in which case every overlap is listed in the error. On success the
+/// origin of `images[entry]` is returned so the caller can point PC at it.
+/// Words go straight to RAM, an image covering the device registers doesn't
+/// print or stop the clock.
+pub fn load_images(memory: &mut Memory, images: &[Image], entry: usize) -> Result<u16, Error> {
+    let origin = match images.get(entry) {
+        Some(image) => image.origin,
+        None => return Err(Error::new(ErrorKind::InvalidInput,
+            format!("entry image {} out of range, {} image(s) given", entry, images.len()))),
+    };
+    if let Some(i) = images.iter().position(|image| image.end() > MEMORY_SIZE) {
+        return Err(Error::new(ErrorKind::InvalidData,
+            format!("image {} does not fit in memory", i)));
+    }
+    let overlaps = find_overlaps(images);
+    if !overlaps.is_empty() {
+        let report: Vec<String> = overlaps.iter().map(|o| o.to_string()).collect();
+        return Err(Error::new(ErrorKind::InvalidData, report.join("; ")));
+    }
+    for image in images {
+        for (i, word) in image.data.iter().enumerate() {
+            memory.memory[image.origin as usize + i] = *word;
+        }
+    }
+    Ok(origin)
+}
+
+/// Read an LC3 object file and load it into memory.
+///
+/// The first word of the image is the origin, the address at which the
+/// remaining words are placed. The origin is returned so the caller can
+/// point PC at it.
+pub fn read_image_file(memory: &mut Memory, image_path: String) -> Result<u16, Error> {
+    load_images(memory, &[Image::from_file(&image_path)?], 0)
+}
+
+/// The symbols of an object file, read from the `.sym` file next to it
+/// (prog.obj -> prog.sym). None if there's no such file.
+pub fn read_symbols_for(image_path: &str) -> Result<Option<SymbolTable>, Error> {
+    let path = Path::new(image_path).with_extension("sym");
+    if !path.exists() {
+        return Ok(None);
+    }
+    SymbolTable::from_file(&path.to_string_lossy()).map(Some)
+}
+
+/// Place an image (origin word followed by data) into memory and return the origin.
+pub fn load_image(memory: &mut Memory, image: &[u16]) -> Result<u16, Error> {
+    load_images(memory, &[Image::from_words(image)?], 0)
+}
+
+/// Convert the big-endian bytes of an object file into words.
+pub fn get_instr_from_buffer(data: &[u8]) -> Result<Vec<u16>, Error> {
+    if !data.len().is_multiple_of(2) {
+        return Err(Error::new(ErrorKind::InvalidData,
+            "input must be a multiple of 2"));
+    }
+    Ok(data
+        .chunks(2)
+        .map(|x| x[1] as u16 | (x[0] as u16) << 8)
+        .collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{MR_DDR, MR_MCR, MCR_CLOCK};
+
+    fn image(words: &[u16]) -> Image {
+        Image::from_words(words).unwrap()
+    }
+
+    #[test]
+    fn test_loading_image_file(){
+        let mut memory = Memory::new();
+        let origin = read_image_file(&mut memory, String::from("./halt.obj")).unwrap();
+        assert_eq!(origin, 0x0300);
+        assert_eq!(memory[0x0300], 0xE005, "first instruction placed at origin");
+        assert_eq!(memory[0x032A], 0x7FFF, "last word placed");
+    }
+
+    #[test]
+    fn test_read_symbols_for(){
+        assert_eq!(read_symbols_for("./lc3os.obj").unwrap().unwrap(), SymbolTable::lc3os());
+        assert_eq!(read_symbols_for("./halt.obj").unwrap(), None);
+    }
+
+    #[test]
+    fn test_load_image(){
+        let mut memory = Memory::new();
+        let origin = load_image(&mut memory, &[0x3000, 0x1234, 0x5678]).unwrap();
+        assert_eq!(origin, 0x3000);
+        assert_eq!(memory[0x3000], 0x1234);
+        assert_eq!(memory[0x3001], 0x5678);
+
+        assert!(load_image(&mut memory, &[]).is_err(), "missing origin");
+        assert!(load_image(&mut memory, &[0xFFFF, 1, 2]).is_err(), "past end of memory");
+        assert_eq!(memory[0xFFFF], 0, "nothing written");
+        assert_eq!(load_image(&mut memory, &[0xFFFE, 1, 2]).unwrap(), 0xFFFE, "fills the last word");
+
+        load_image(&mut memory, &[MR_DDR, b'!' as u16]).unwrap();
+        assert_eq!(memory.devices.take_output(), None, "loading doesn't print");
+        assert_eq!(memory.memory[MR_DDR as usize], b'!' as u16);
+        load_image(&mut memory, &[MR_MCR, 0]).unwrap();
+        assert_eq!(memory[MR_MCR], MCR_CLOCK, "loading doesn't stop the clock");
+    }
+
+    #[test]
+    fn test_get_instr_from_buffer(){
+        assert_eq!(get_instr_from_buffer(&[0x30, 0x00, 0xF0, 0x25]).unwrap(), vec![0x3000, 0xF025]);
+        assert!(get_instr_from_buffer(&[0x30]).is_err());
+    }
+
+    #[test]
+    fn test_load_images(){
+        let mut memory = Memory::new();
+        let images = [image(&[0x3000, 0xF025]), image(&[0x4000, 7, 8, 9])];
+        assert_eq!(load_images(&mut memory, &images, 1).unwrap(), 0x4000, "entry selects origin");
+        assert_eq!(memory[0x3000], 0xF025);
+        assert_eq!(memory[0x4002], 9);
+        assert!(load_images(&mut memory, &images, 2).is_err(), "entry out of range");
+    }
+
+    #[test]
+    fn test_lc3os(){
+        let os = Image::lc3os();
+        assert_eq!(os.origin, 0x0000);
+        assert_eq!(os.data[0x25], 0x0241, "HALT vector");
+        assert_eq!(os.data[0x100], 0x024F, "privilege exception handler");
+        assert!(os.end() <= 0x3000, "fits in system space");
+        assert_eq!(os.to_bytes(), LC3OS);
+    }
+
+    #[test]
+    fn test_find_overlaps(){
+        let images = [
+            image(&[0x3000, 1, 2, 3, 4]),     // x3000-x3003
+            image(&[0x3004, 5]),              // x3004, touches but doesn't overlap
+            image(&[0x3002, 6, 7, 8]),        // x3002-x3004
+        ];
+        assert_eq!(find_overlaps(&images), vec![
+            Overlap { first: 0, second: 2, start: 0x3002, end: 0x3003 },
+            Overlap { first: 1, second: 2, start: 0x3004, end: 0x3004 },
+        ]);
+
+        let mut memory = Memory::new();
+        let err = load_images(&mut memory, &images, 0).unwrap_err();
+        assert_eq!(err.to_string(),
+            "image 0 overlaps image 2 at x3002-x3003; image 1 overlaps image 2 at x3004-x3004");
+        assert_eq!(memory[0x3000], 0, "nothing loaded on overlap");
+    }
+}
@@ -1,86 +1,605 @@
-mod defs;
-mod operations;
-
-use defs::memory::Memory;
-use defs::register::*;
-use operations::executor::*;
 use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::io::{Error, ErrorKind, Read};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::time::Duration;
+use virtual_machine::*;
+
+/// Process exit statuses, a program that halts exits with 0 (or R0 with --exit-r0).
+const EXIT_FAULT: i32 = 1;                  // fault, exception without a handler or halting in one, or the source didn't assemble or link
+const EXIT_USAGE: i32 = 2;                  // bad command line
+const EXIT_LOAD: i32 = 3;                   // an image or file couldn't be read or loaded
+const EXIT_BUDGET: i32 = 4;                 // --max-instructions ran out
+const EXIT_TIMEOUT: i32 = 5;                // --timeout ran out
+const EXIT_LOOP: i32 = 6;                   // --detect-loops found one
+const EXIT_INPUT: i32 = 7;                  // input exhausted with --on-eof halt
+const EXIT_STOPPED: i32 = 8;                // stopped for any other reason
 
 ///
 /// Virutal machine implementing LC3 (Little Computer - 3)
 ///
+/// usage: virtual_machine [options] image.obj [image.obj ...]
+///        virtual_machine debug [options] image.obj [image.obj ...]
+///        virtual_machine gdb [--listen <host:port> | --socket <path>] [options] image.obj [image.obj ...]
+///        virtual_machine asm [-c] [-o image.obj] program.asm
+///        virtual_machine link [-o image.obj] [--origin <address>] module.o [module.o ...]
+///        virtual_machine disasm [--sym file.sym] image.obj
+///
+/// Every image is loaded at its own origin, execution starts at the origin
+/// of the n-th image (counting from 0, the first one by default).
+///
+/// options:
+///   --entry <n>                     start at the origin of the n-th image
+///   --traps <builtin|authentic>     service traps in Rust (default) or with an OS image
+///   --os <image.obj>                OS image for authentic traps, the bundled one by default
+///   --input <text>                  keyboard input, \n \r \t \\ and \0 escapes are understood
+///   --input-file <path>             keyboard input read from a file
+///   --output <path>                 write the program's output to a file instead of stdout
+///   --on-eof <halt|fault|ffff>      when the program wants a key after the input ran out:
+///                                   stop, report a fault (default) or hand it xFFFF
+///   --max-instructions <n>          stop after n instructions (exit status 4)
+///   --timeout <seconds>             stop after this much wall-clock time (exit status 5)
+///   --detect-loops                  stop on a branch or jump to itself (exit status 6)
+///   --exit-r0                       exit with the low byte of R0 when the program halts
+///   --sym <file.sym>                labels to show addresses with, prog.sym is read
+///                                   along with prog.obj when it exists
+///   --trace                         log every instruction to stderr
+///
+/// exit status: 0 halted, 1 fault (or halted in an exception handler), 2 usage,
+/// 3 load error, 4 instruction budget exceeded, 5 timeout, 6 infinite loop,
+/// 7 input exhausted, 8 stopped for any other reason.
+///
+/// With --input or --input-file the terminal is left alone, nothing is read
+/// from stdin.
+///
+/// debug and gdb take the same options except --max-instructions, --timeout
+/// and --exit-r0.
+///
+/// debug loads the images the same way and stops before the first
+/// instruction, waiting for debugger commands (help lists them). Without
+/// --input or --input-file the program reads the lines after the command
+/// that runs it.
+///
+/// gdb loads them the same way and waits for a GDB remote protocol client on
+/// --listen (127.0.0.1:1234 by default) or the Unix socket --socket, then
+/// serves it until it detaches.
+///
+/// asm assembles program.asm into program.obj (or the -o path) and writes its
+/// labels to program.sym, files it .INCLUDEs are looked up next to it. With -c
+/// it writes the relocatable module program.o instead, a program that
+/// .IMPORTs labels has to be assembled that way and linked.
+///
+/// link places the modules one after the other, from the origin of the first
+/// one or --origin, and writes the image and its labels like asm does.
+///
+/// disasm prints every word of image.obj as an instruction, with the labels
+/// of image.sym (and the --sym files) when there are any.
 fn main() {
-    println!("Starting VM........");
-
-    // define the RAM
-    let max: usize = 65535;
-    let mut memory = Memory::new(max);
-
-    // declare registers and set PC to the default starting position
-    let _reg_count = 10;
-    let pc_start: u16 = 0x3000;
-    let mut reg: Register = Default::default();
-    reg[Reg::R_PC] = pc_start;
-
-    // From now on the process is fairly simple
-    // 1- load the instruction from the RAM (PC)
-    // 2- increment PC
-    // 3- inspect the opcode to determine the operation then perform it
-    // 4- goto 1
-    let mut running: bool = true;
-    while running {
-        let instr: u16 = memory[reg[Reg::R_PC]];                // fetch instruction
-        reg[Reg::R_PC] += 1;                                    // increment program counter
-        execute(instr, &mut reg, &mut memory, &mut running);        // execute instruction
-    }
-}
-
-pub fn read_image_file(memory: &mut Memory, image_path: String) -> Result<Vec<u16>, Error> {
-    let mut buffer = Vec::new();
-    File::open(image_path)?.read_to_end(&mut buffer)?;
-    get_instr_from_buffer(&buffer)
-}
-
-pub fn get_instr_from_buffer(data: &[u8]) -> Result<Vec<u16>, Error> {
-    if data.len() % 2 != 0 {
-        return Err(Error::new(ErrorKind::InvalidData,
-            "input must be a multiple of 2"));
-    }
-    Ok(data
-        .chunks(2)
-        .map(|x| x[1] as u16 | (x[0] as u16) << 8)
-        .collect())
-}
-
-pub fn print_instr(x: u16) {
-    let mut number = x.clone();
-    let mut i = 16;
-    while i > 0 {
-        let bit = (number & 0b1000000000000000) >> 15;
-        print!("{}", bit);
-        number = number << 1;
-        i -= 1;
-    }
-    println!("");
-}
-
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    
-    #[test]
-    fn test_loading_image_file(){
-        let max: usize = 65535;
-        let mut memory = Memory::new(max);
-        if let Ok(instructions) = read_image_file(&mut memory, String::from("./halt.obj")) {
-            for instr in instructions.clone(){
-                print_instr(instr);
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("asm") => assemble_file(&args),
+        Some("link") => link_files(&args),
+        Some("disasm") => disassemble_file(&args),
+        Some("debug") => run(&args, Mode::Debug),
+        Some("gdb") => run(&args, Mode::Gdb),
+        _ => run(&args, Mode::Run),
+    }
+}
+
+/// Options about how a run ends, the debugger and gdb decide that themselves.
+const RUN_ONLY: [&str; 3] = ["--max-instructions", "--timeout", "--exit-r0"];
+
+/// What `run` does with the loaded images.
+#[derive(PartialEq)]
+enum Mode {
+    Run,
+    Debug,
+    Gdb,
+}
+
+/// Load the images and run them, or hand them to the debugger or gdb.
+fn run(args: &[String], mode: Mode) {
+    let mut paths: Vec<String> = Vec::new();
+    let mut entry: usize = 0;
+    let mut trap_mode = TrapMode::Builtin;
+    let mut os_path: Option<String> = None;
+    let mut input: Option<Vec<u8>> = None;
+    let mut output_path: Option<String> = None;
+    let mut on_eof = EofPolicy::Fault;
+    let mut budget: Option<u64> = None;
+    let mut time_limit: Option<Duration> = None;
+    let mut detect_loops = false;
+    let mut exit_r0 = false;
+    let mut sym_paths: Vec<String> = Vec::new();
+    let mut trace = false;
+    let mut listen = String::from("127.0.0.1:1234");
+    let mut socket: Option<String> = None;
+    let mut i = if mode == Mode::Run { 1 } else { 2 };
+    while i < args.len() {
+        if mode != Mode::Run && RUN_ONLY.contains(&args[i].as_str()) {
+            eprintln!("{} only applies when running without debug or gdb", args[i]);
+            usage(&args[0]);
+        } else if args[i] == "--entry" {
+            i += 1;
+            entry = match args.get(i).and_then(|n| n.parse().ok()) {
+                Some(n) => n,
+                None => usage(&args[0]),
+            };
+        } else if args[i] == "--traps" {
+            i += 1;
+            trap_mode = match args.get(i).map(|s| s.as_str()) {
+                Some("builtin") => TrapMode::Builtin,
+                Some("authentic") => TrapMode::Authentic,
+                _ => usage(&args[0]),
+            };
+        } else if args[i] == "--os" {
+            i += 1;
+            os_path = Some(args.get(i).cloned().unwrap_or_else(|| usage(&args[0])));
+            trap_mode = TrapMode::Authentic;
+        } else if args[i] == "--input" {
+            i += 1;
+            let text = args.get(i).unwrap_or_else(|| usage(&args[0]));
+            input = Some(unescape(text));
+        } else if args[i] == "--input-file" {
+            i += 1;
+            let path = args.get(i).unwrap_or_else(|| usage(&args[0]));
+            match std::fs::read(path) {
+                Ok(bytes) => input = Some(bytes),
+                Err(e) => {
+                    eprintln!("failed to read {}: {}", path, e);
+                    std::process::exit(EXIT_LOAD);
+                }
+            }
+        } else if args[i] == "--output" {
+            i += 1;
+            output_path = Some(args.get(i).cloned().unwrap_or_else(|| usage(&args[0])));
+        } else if args[i] == "--on-eof" {
+            i += 1;
+            on_eof = match args.get(i).map(|s| s.as_str()) {
+                Some("halt") => EofPolicy::Halt,
+                Some("fault") => EofPolicy::Fault,
+                Some("ffff") => EofPolicy::Value,
+                _ => usage(&args[0]),
+            };
+        } else if args[i] == "--max-instructions" {
+            i += 1;
+            budget = Some(args.get(i).and_then(|n| n.parse().ok()).unwrap_or_else(|| usage(&args[0])));
+        } else if args[i] == "--timeout" {
+            i += 1;
+            let seconds = args.get(i)
+                .and_then(|s| s.parse().ok())
+                .and_then(|s: f64| Duration::try_from_secs_f64(s).ok())
+                .unwrap_or_else(|| usage(&args[0]));
+            time_limit = Some(seconds);
+        } else if args[i] == "--detect-loops" {
+            detect_loops = true;
+        } else if args[i] == "--exit-r0" {
+            exit_r0 = true;
+        } else if args[i] == "--sym" {
+            i += 1;
+            sym_paths.push(args.get(i).cloned().unwrap_or_else(|| usage(&args[0])));
+        } else if args[i] == "--trace" {
+            trace = true;
+        } else if mode == Mode::Gdb && args[i] == "--listen" {
+            i += 1;
+            listen = args.get(i).cloned().unwrap_or_else(|| usage(&args[0]));
+        } else if mode == Mode::Gdb && args[i] == "--socket" {
+            i += 1;
+            socket = Some(args.get(i).cloned().unwrap_or_else(|| usage(&args[0])));
+        } else {
+            paths.push(args[i].clone());
+        }
+        i += 1;
+    }
+    if paths.is_empty() || entry >= paths.len() {
+        usage(&args[0]);
+    }
+    if mode == Mode::Run {
+        println!("Starting VM........");
+    }
+
+    // load the program images, the entry image's origin is where execution starts
+    let mut images: Vec<Image> = Vec::new();
+    for path in &paths {
+        match Image::from_file(path) {
+            Ok(image) => images.push(image),
+            Err(e) => {
+                eprintln!("failed to load {}: {}", path, e);
+                std::process::exit(EXIT_LOAD);
+            }
+        }
+    }
+    // authentic traps need an OS, it's loaded after the programs so
+    // --entry still counts program images only
+    if trap_mode == TrapMode::Authentic {
+        let os = match &os_path {
+            Some(path) => Image::from_file(path),
+            None => Ok(Image::lc3os()),
+        };
+        match os {
+            Ok(os) => images.push(os),
+            Err(e) => {
+                eprintln!("failed to load {}: {}", os_path.unwrap_or_default(), e);
+                std::process::exit(EXIT_LOAD);
+            }
+        }
+    }
+    let output: Option<Box<dyn Write>> = match &output_path {
+        Some(path) => match File::create(path) {
+            Ok(file) => Some(Box::new(BufWriter::new(file))),
+            Err(e) => {
+                eprintln!("failed to create {}: {}", path, e);
+                std::process::exit(EXIT_LOAD);
+            }
+        },
+        None => None,
+    };
+    let interactive = input.is_none();
+    let console: Box<dyn Console> = match (input, output) {
+        // the debugger reads its commands through the buffer of io::stdin(),
+        // the program has to read what follows them from the same buffer
+        (None, output) if mode == Mode::Debug => Box::new(ScriptedConsole::new(io::stdin(),
+            output.unwrap_or_else(|| Box::new(io::stdout())))),
+        (None, None) => Box::new(StdConsole),
+        (None, Some(output)) => Box::new(RedirectedConsole::new(StdConsole, output)),
+        (Some(bytes), output) => Box::new(ScriptedConsole::new(io::Cursor::new(bytes),
+            output.unwrap_or_else(|| Box::new(io::stdout())))),
+    };
+    let mut vm = Vm::with_console(console);
+    vm.trap_mode = trap_mode;
+    vm.on_eof = on_eof;
+    vm.time_limit = time_limit;
+    vm.detect_loops = detect_loops;
+    if let Err(e) = vm.load_images(&images, entry) {
+        eprintln!("failed to load images: {}", e);
+        std::process::exit(EXIT_LOAD);
+    }
+    load_symbols(&mut vm.symbols, &paths, trap_mode, &os_path, &sym_paths);
+    if trace {
+        vm.trace = Some(Box::new(io::stderr()));
+    }
+    if mode == Mode::Gdb {
+        let result = match &socket {
+            Some(path) => serve_socket(vm, path),
+            None => serve_tcp(vm, &listen),
+        };
+        if let Err(e) = result {
+            eprintln!("gdb: {}", e);
+            std::process::exit(EXIT_FAULT);
+        }
+        return;
+    }
+    if mode == Mode::Debug {
+        let mut debugger = Debugger::new(vm);
+        if let Err(e) = debugger.repl(&mut StdinLines::default(), &mut io::stdout()) {
+            eprintln!("debugger: {}", e);
+            std::process::exit(EXIT_FAULT);
+        }
+        return;
+    }
+
+    // keys go to the program as they're pressed, the terminal is restored
+    // once the run is over (process::exit skips destructors, drop it first)
+    let terminal = if interactive { Some(RawTerminal::enable()) } else { None };
+    let reason = match budget {
+        Some(budget) => vm.run_for(budget),
+        None => vm.run(),
+    };
+    drop(terminal);
+    if let Err(e) = vm.console.flush() {
+        eprintln!("failed to write output: {}", e);
+        std::process::exit(EXIT_FAULT);
+    }
+
+    match reason {
+        StopReason::Halted if vm.exception.is_some() => {
+            let (pc, e) = vm.exception.unwrap();
+            eprintln!("halted after an exception at {}: {}", vm.symbols.describe(pc), e);
+            std::process::exit(EXIT_FAULT);
+        }
+        StopReason::Halted if exit_r0 => std::process::exit((vm.reg[Reg::R_R0] & 0xFF) as i32),
+        StopReason::Halted => {}
+        StopReason::Fault(e) => {
+            eprintln!("fault at {}: {}", vm.symbols.describe(vm.reg[Reg::R_PC]), e);
+            std::process::exit(EXIT_FAULT);
+        }
+        StopReason::BudgetExhausted => {
+            eprintln!("instruction budget exhausted at {}", vm.symbols.describe(vm.reg[Reg::R_PC]));
+            std::process::exit(EXIT_BUDGET);
+        }
+        StopReason::Timeout => {
+            eprintln!("timed out at {}", vm.symbols.describe(vm.reg[Reg::R_PC]));
+            std::process::exit(EXIT_TIMEOUT);
+        }
+        StopReason::InfiniteLoop(pc) => {
+            eprintln!("infinite loop at {}", vm.symbols.describe(pc));
+            std::process::exit(EXIT_LOOP);
+        }
+        StopReason::InputExhausted => {
+            eprintln!("input exhausted at {}", vm.symbols.describe(vm.reg[Reg::R_PC]));
+            std::process::exit(EXIT_INPUT);
+        }
+        reason => {
+            eprintln!("stopped: {:?}", reason);
+            std::process::exit(EXIT_STOPPED);
+        }
+    }
+}
+
+/// Standard input a line at a time for the debugger. Only the line is
+/// taken out of the buffer of io::stdin(), whatever follows it is left there
+/// for the program being debugged.
+#[derive(Default)]
+struct StdinLines {
+    line: Vec<u8>,
+    consumed: usize,
+}
+
+impl Read for StdinLines {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buffer)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.consumed == self.line.len() {
+            self.line.clear();
+            self.consumed = 0;
+            io::stdin().lock().read_until(b'\n', &mut self.line)?;
+        }
+        Ok(&self.line[self.consumed..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed = (self.consumed + amount).min(self.line.len());
+    }
+}
+
+/// Wait for gdb on a TCP port and serve it.
+fn serve_tcp(vm: Vm, address: &str) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind(address)?;
+    eprintln!("waiting for gdb on {}", listener.local_addr()?);
+    let (mut connection, _) = listener.accept()?;
+    connection.set_nodelay(true)?;
+    GdbStub::new(vm).serve(&mut connection)
+}
+
+/// Wait for gdb on a Unix socket and serve it, the socket is removed after.
+#[cfg(unix)]
+fn serve_socket(vm: Vm, path: &str) -> io::Result<()> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    eprintln!("waiting for gdb on {}", path);
+    let result = listener.accept().and_then(|(mut connection, _)| GdbStub::new(vm).serve(&mut connection));
+    let _ = std::fs::remove_file(path);
+    result
+}
+
+#[cfg(not(unix))]
+fn serve_socket(_vm: Vm, _path: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not available here"))
+}
+
+/// The asm subcommand.
+fn assemble_file(args: &[String]) {
+    let mut source_path: Option<&String> = None;
+    let mut output_path: Option<String> = None;
+    let mut relocatable = false;
+    let mut i = 2;
+    while i < args.len() {
+        if args[i] == "-c" {
+            relocatable = true;
+        } else if args[i] == "-o" {
+            i += 1;
+            output_path = Some(args.get(i).cloned().unwrap_or_else(|| usage(&args[0])));
+        } else if source_path.is_none() {
+            source_path = Some(&args[i]);
+        } else {
+            usage(&args[0]);
+        }
+        i += 1;
+    }
+    let source_path = source_path.unwrap_or_else(|| usage(&args[0]));
+    let extension = if relocatable { "o" } else { "obj" };
+    let output_path = output_path.unwrap_or_else(|| with_extension(source_path, extension));
+    let source = match std::fs::read_to_string(source_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", source_path, e);
+            std::process::exit(EXIT_LOAD);
+        }
+    };
+    let dir = std::path::Path::new(source_path).parent().unwrap_or_else(|| std::path::Path::new(""));
+    let program = match assemble_in(&source, dir) {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprint!("{}", diagnostic.render(source_path, &source));
+            }
+            let errors = if diagnostics.len() == 1 { "error" } else { "errors" };
+            eprintln!("{}: {} {}, nothing written", source_path, diagnostics.len(), errors);
+            std::process::exit(EXIT_FAULT);
+        }
+    };
+    if relocatable {
+        match program.object().to_bytes() {
+            Ok(bytes) => write_file(&output_path, &bytes),
+            Err(e) => {
+                eprintln!("{}: {}, nothing written", source_path, e);
+                std::process::exit(EXIT_FAULT);
             }
         }
-         
+        return;
+    }
+    if !program.imports.is_empty() {
+        eprintln!("{}: imports {}, assemble it with -c and link it", source_path, program.imports.join(", "));
+        std::process::exit(EXIT_FAULT);
+    }
+    write_image(&output_path, &program.image(), &program.symbol_table());
+}
+
+/// The link subcommand.
+fn link_files(args: &[String]) {
+    let mut paths: Vec<&String> = Vec::new();
+    let mut output_path: Option<String> = None;
+    let mut origin: Option<u16> = None;
+    let mut i = 2;
+    while i < args.len() {
+        if args[i] == "-o" {
+            i += 1;
+            output_path = Some(args.get(i).cloned().unwrap_or_else(|| usage(&args[0])));
+        } else if args[i] == "--origin" {
+            i += 1;
+            origin = Some(args.get(i).and_then(|a| parse_number(a)).unwrap_or_else(|| usage(&args[0])));
+        } else {
+            paths.push(&args[i]);
+        }
+        i += 1;
+    }
+    if paths.is_empty() {
+        usage(&args[0]);
+    }
+    let mut modules = Vec::new();
+    for path in &paths {
+        match Object::from_file(path) {
+            Ok(object) => modules.push((path.to_string(), object)),
+            Err(e) => {
+                eprintln!("failed to read {}: {}", path, e);
+                std::process::exit(EXIT_LOAD);
+            }
+        }
+    }
+    let (image, symbols) = match link(&modules, origin) {
+        Ok(linked) => linked,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            let count = if errors.len() == 1 { "error" } else { "errors" };
+            eprintln!("{} {}, nothing written", errors.len(), count);
+            std::process::exit(EXIT_FAULT);
+        }
+    };
+    let output_path = output_path.unwrap_or_else(|| with_extension(paths[0], "obj"));
+    write_image(&output_path, &image, &symbols);
+}
+
+/// Write an image and, next to it, its labels.
+fn write_image(path: &str, image: &Image, symbols: &SymbolTable) {
+    write_file(path, &image.to_bytes());
+    write_file(&with_extension(path, "sym"), symbols.to_sym().as_bytes());
+}
+
+fn write_file(path: &str, contents: &[u8]) {
+    if let Err(e) = std::fs::write(path, contents) {
+        eprintln!("failed to write {}: {}", path, e);
+        std::process::exit(EXIT_LOAD);
+    }
+}
+
+/// The disasm subcommand.
+fn disassemble_file(args: &[String]) {
+    let mut image_path: Option<String> = None;
+    let mut sym_paths: Vec<String> = Vec::new();
+    let mut i = 2;
+    while i < args.len() {
+        if args[i] == "--sym" {
+            i += 1;
+            sym_paths.push(args.get(i).cloned().unwrap_or_else(|| usage(&args[0])));
+        } else if image_path.is_none() {
+            image_path = Some(args[i].clone());
+        } else {
+            usage(&args[0]);
+        }
+        i += 1;
+    }
+    let image_path = image_path.unwrap_or_else(|| usage(&args[0]));
+    let image = match Image::from_file(&image_path) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", image_path, e);
+            std::process::exit(EXIT_LOAD);
+        }
+    };
+    let mut symbols = SymbolTable::new();
+    load_symbols(&mut symbols, std::slice::from_ref(&image_path), TrapMode::Builtin, &None, &sym_paths);
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for line in disassemble_image(&image, &symbols) {
+        if writeln!(out, "{}", line).is_err() {
+            return;                                                     // stdout closed, e.g. piped to head
+        }
+    }
+}
+
+/// Gather the labels of the loaded images: the .sym next to each image,
+/// the OS symbols and the --sym files, later ones win.
+fn load_symbols(symbols: &mut SymbolTable, paths: &[String], trap_mode: TrapMode,
+                os_path: &Option<String>, sym_paths: &[String]) {
+    let mut images: Vec<&String> = paths.iter().collect();
+    if trap_mode == TrapMode::Authentic {
+        match os_path {
+            Some(path) => images.push(path),
+            None => symbols.extend(&SymbolTable::lc3os()),
+        }
+    }
+    for path in images {
+        match read_symbols_for(path) {
+            Ok(Some(table)) => symbols.extend(&table),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("failed to read the symbols of {}: {}", path, e);
+                std::process::exit(EXIT_LOAD);
+            }
+        }
+    }
+    for path in sym_paths {
+        match SymbolTable::from_file(path) {
+            Ok(table) => symbols.extend(&table),
+            Err(e) => {
+                eprintln!("failed to read {}: {}", path, e);
+                std::process::exit(EXIT_LOAD);
+            }
+        }
+    }
+}
+
+/// `path` with its extension replaced (or added).
+fn with_extension(path: &str, extension: &str) -> String {
+    std::path::Path::new(path).with_extension(extension).to_string_lossy().into_owned()
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} [--entry <n>] [--traps builtin|authentic] [--os <os.obj>] \
+        [--input <text> | --input-file <path>] [--output <path>] [--on-eof halt|fault|ffff] \
+        [--max-instructions <n>] [--timeout <seconds>] [--detect-loops] [--exit-r0] [--sym <file.sym>] [--trace] <image.obj> [image.obj ...]\n\
+        usage: {} debug [options] <image.obj> [image.obj ...]\n\
+        usage: {} gdb [--listen <host:port> | --socket <path>] [options] <image.obj> [image.obj ...]\n\
+        usage: {} asm [-c] [-o <image.obj>] <program.asm>\n\
+        usage: {} link [-o <image.obj>] [--origin <address>] <module.o> [module.o ...]\n\
+        usage: {} disasm [--sym <file.sym>] <image.obj>", program, program, program, program, program, program);
+    std::process::exit(EXIT_USAGE);
+}
+
+/// The bytes of `--input` text with its backslash escapes resolved,
+/// an unknown escape is kept as is.
+fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chars = text.bytes();
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b'r') => bytes.push(b'\r'),
+            Some(b't') => bytes.push(b'\t'),
+            Some(b'0') => bytes.push(0),
+            Some(b'\\') => bytes.push(b'\\'),
+            Some(other) => bytes.extend_from_slice(&[b'\\', other]),
+            None => bytes.push(b'\\'),
+        }
     }
+    bytes
 }
@@ -0,0 +1,214 @@
+//! Relocatable object modules, what `asm -c` writes and the linker reads.
+//!
+//! A module is a file of big-endian words like an image:
+//!
+//! ```text
+//! x4C33 x4F42         magic, "L3OB"
+//! origin              where the module was assembled to go
+//! n, n words          the code
+//! n, n symbols        address, flags (1 when exported), name
+//! n, n imports        name
+//! n, n relocations    offset, kind (0 PCoffset9, 1 PCoffset11, 2 .FILL), import (xFFFF for none)
+//! ```
+//!
+//! Names are their length followed by one character per word. Offsets count
+//! words from the start of the code.
+
+use crate::loader::get_instr_from_buffer;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+
+const MAGIC: [u16; 2] = [0x4C33, 0x4F42];
+
+/// What the linker has to patch in a word.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    /// the low 9 bits, an offset from the incremented PC (LD, ST, BR, ...).
+    PcOffset9,
+    /// the low 11 bits, an offset from the incremented PC (JSR).
+    PcOffset11,
+    /// the whole word, an address (.FILL LABEL).
+    Fill,
+}
+
+impl RelocationKind {
+    fn code(self) -> u16 {
+        match self {
+            RelocationKind::PcOffset9 => 0,
+            RelocationKind::PcOffset11 => 1,
+            RelocationKind::Fill => 2,
+        }
+    }
+
+    fn from_code(code: u16) -> Option<Self> {
+        match code {
+            0 => Some(RelocationKind::PcOffset9),
+            1 => Some(RelocationKind::PcOffset11),
+            2 => Some(RelocationKind::Fill),
+            _ => None,
+        }
+    }
+}
+
+/// A word the linker has to patch once it knows where everything goes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    /// the word, counted from the start of the code.
+    pub offset: u16,
+    pub kind: RelocationKind,
+    /// the imported label the word refers to, None for a label of the
+    /// module itself (only a .FILL needs moving then).
+    pub symbol: Option<String>,
+}
+
+/// An assembled module that can be placed anywhere by the linker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// every label of the module and its address (at `origin`).
+    pub symbols: Vec<(String, u16)>,
+    /// the labels other modules can refer to.
+    pub exports: Vec<String>,
+    /// the labels the module expects another one to export.
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    /// The bytes of the module file, an error if something has more
+    /// entries (or a name more characters) than a word can count.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut words = MAGIC.to_vec();
+        words.push(self.origin);
+        words.push(count(self.words.len(), "code")?);
+        words.extend(&self.words);
+        words.push(count(self.symbols.len(), "symbol table")?);
+        for (name, address) in &self.symbols {
+            words.push(*address);
+            words.push(self.exports.contains(name) as u16);
+            push_name(&mut words, name)?;
+        }
+        words.push(count(self.imports.len(), "import list")?);
+        for name in &self.imports {
+            push_name(&mut words, name)?;
+        }
+        words.push(count(self.relocations.len(), "relocation list")?);
+        for relocation in &self.relocations {
+            let import = relocation.symbol.as_ref()
+                .and_then(|name| self.imports.iter().position(|import| import == name))
+                .map_or(0xFFFF, |i| i as u16);
+            words.extend([relocation.offset, relocation.kind.code(), import]);
+        }
+        Ok(words.iter().flat_map(|word| word.to_be_bytes()).collect())
+    }
+
+    /// Read a module from the bytes of its file.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let words = get_instr_from_buffer(data)?;
+        if !words.starts_with(&MAGIC) {
+            return Err(invalid("not an object module"));
+        }
+        let mut reader = Reader { words: &words[2..] };
+        let origin = reader.word()?;
+        let count = reader.word()?;
+        let code = (0..count).map(|_| reader.word()).collect::<Result<Vec<_>, _>>()?;
+        let mut object = Object { origin, words: code, symbols: Vec::new(), exports: Vec::new(), imports: Vec::new(), relocations: Vec::new() };
+        for _ in 0..reader.word()? {
+            let address = reader.word()?;
+            let exported = reader.word()? & 1 == 1;
+            let name = reader.name()?;
+            if exported {
+                object.exports.push(name.clone());
+            }
+            object.symbols.push((name, address));
+        }
+        for _ in 0..reader.word()? {
+            object.imports.push(reader.name()?);
+        }
+        for _ in 0..reader.word()? {
+            let offset = reader.word()?;
+            let kind = RelocationKind::from_code(reader.word()?).ok_or_else(|| invalid("unknown relocation kind"))?;
+            let symbol = match reader.word()? {
+                0xFFFF => None,
+                i => Some(object.imports.get(i as usize).cloned().ok_or_else(|| invalid("relocation names a missing import"))?),
+            };
+            if offset as usize >= object.words.len() {
+                return Err(invalid("relocation past the end of the code"));
+            }
+            object.relocations.push(Relocation { offset, kind, symbol });
+        }
+        Ok(object)
+    }
+
+    /// Read a module file from disk.
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+        Self::from_bytes(&buffer)
+    }
+}
+
+fn push_name(words: &mut Vec<u16>, name: &str) -> Result<(), Error> {
+    words.push(count(name.len(), "symbol name")?);
+    words.extend(name.bytes().map(u16::from));
+    Ok(())
+}
+
+/// `len` as the word that counts it, `what` names it in the error.
+fn count(len: usize, what: &str) -> Result<u16, Error> {
+    if len > u16::MAX as usize {
+        return Err(invalid(&format!("{} has {} entries, an object module holds at most 65535", what, len)));
+    }
+    Ok(len as u16)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+struct Reader<'a> {
+    words: &'a [u16],
+}
+
+impl Reader<'_> {
+    fn word(&mut self) -> Result<u16, Error> {
+        let (first, rest) = self.words.split_first().ok_or_else(|| invalid("object module is cut short"))?;
+        self.words = rest;
+        Ok(*first)
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        let len = self.word()?;
+        let bytes = (0..len).map(|_| self.word().map(|w| w as u8)).collect::<Result<Vec<_>, _>>()?;
+        String::from_utf8(bytes).map_err(|_| invalid("symbol name is not text"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip(){
+        let object = Object {
+            origin: 0x3000,
+            words: vec![0x2000, 0x4800, 0x0000],
+            symbols: vec![(String::from("MAIN"), 0x3000), (String::from("PTR"), 0x3002)],
+            exports: vec![String::from("MAIN")],
+            imports: vec![String::from("PRINT")],
+            relocations: vec![
+                Relocation { offset: 1, kind: RelocationKind::PcOffset11, symbol: Some(String::from("PRINT")) },
+                Relocation { offset: 2, kind: RelocationKind::Fill, symbol: None },
+            ],
+        };
+        let bytes = object.to_bytes().unwrap();
+        assert_eq!(Object::from_bytes(&bytes).unwrap(), object);
+        assert!(Object::from_bytes(&bytes[..20]).is_err(), "cut short");
+        assert!(Object::from_bytes(&[0x30, 0x00]).is_err(), "an image isn't a module");
+
+        let huge = Object { origin: 0, words: vec![0; 0x10000], ..object };
+        assert_eq!(huge.to_bytes().unwrap_err().to_string(), "code has 65536 entries, an object module holds at most 65535");
+    }
+}
@@ -1,4 +1,3 @@
-use crate::defs::memory::*;
 use crate::defs::register::*;
 use crate::operations::helper::*;
 
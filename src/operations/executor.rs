@@ -1,26 +1,58 @@
+use crate::console::Console;
+use crate::defs::error::VmError;
 use crate::defs::register::*;
 use crate::defs::memory::*;
 use crate::defs::opcode::*;
+use crate::defs::psr::Psr;
+use crate::defs::traps::TrapMode;
 
-pub fn execute(instr: u16, reg:&mut Register,memory: &mut Memory, running: &mut bool) {
+/// Decode an instruction and execute it.
+/// `running` is cleared once the program halts, `trap_mode` picks how TRAP is
+/// serviced and builtin traps do their I/O through `console`.
+pub fn execute(instr: u16, reg:&mut Register, psr: &mut Psr, memory: &mut Memory, running: &mut bool,
+               trap_mode: TrapMode, console: &mut dyn Console) -> Result<(), VmError> {
     let operation: u16 = instr >> 12;
 
     match Opcode::from_u16(operation) {
-        Opcode::OP_ST    => super::st::op_st(&reg, instr, memory),
-        Opcode::OP_STI   => super::sti::op_sti(&reg, instr, memory),
-        Opcode::OP_STR   => super::str::op_str(&reg, instr, memory),
-        Opcode::OP_BR    => super::br::op_br(reg, instr),
-        Opcode::OP_LD    => super::ld::op_ld(reg, instr, memory),
-        Opcode::OP_ADD   => super::add::op_add(reg, instr),
-        Opcode::OP_AND   => super::and::op_and(reg, instr),
-        Opcode::OP_JMP   => super::jmp::op_jmp(reg, instr),
-        Opcode::OP_JSR   => super::jsr::op_jsr(reg, instr),
-        Opcode::OP_LDI   => super::ldi::op_ldi(reg, instr, memory),
-        Opcode::OP_LDR   => super::ldr::op_ldr(reg, instr, memory),
-        Opcode::OP_LEA   => super::lea::op_lea(reg, instr),
-        Opcode::OP_NOT   => super::not::op_not(reg, instr),
-       // Opcode::OP_RES   => super::op_res(),
-       //  Opcode::OP_RTI   => super:: op_rti(),
-        Opcode::OP_TRAP  =>  *running = super::traps::op_trap(reg, instr, memory),
+        Opcode::OP_ST          => super::st::op_st(reg, instr, memory)?,
+        Opcode::OP_STI         => super::sti::op_sti(reg, instr, memory)?,
+        Opcode::OP_STR         => super::str::op_str(reg, instr, memory)?,
+        Opcode::OP_BR          => super::br::op_br(reg, instr),
+        Opcode::OP_LD          => super::ld::op_ld(reg, instr, memory)?,
+        Opcode::OP_ADD         => super::add::op_add(reg, instr),
+        Opcode::OP_AND         => super::and::op_and(reg, instr),
+        Opcode::OP_JMP         => super::jmp::op_jmp(reg, instr),
+        Opcode::OP_JSR         => super::jsr::op_jsr(reg, instr),
+        Opcode::OP_LDI         => super::ldi::op_ldi(reg, instr, memory)?,
+        Opcode::OP_LDR         => super::ldr::op_ldr(reg, instr, memory)?,
+        Opcode::OP_LEA         => super::lea::op_lea(reg, instr),
+        Opcode::OP_NOT         => super::not::op_not(reg, instr),
+        Opcode::OP_RES         => return Err(VmError::ReservedInstruction(instr)),
+        Opcode::OP_RTI         => super::rti::op_rti(reg, psr, memory)?,
+        Opcode::OP_TRAP        =>  *running = super::traps::op_trap(reg, psr, instr, memory, trap_mode, console)?,
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn test_execute_faults(){
+        let mut reg = Register::default();
+        let mut psr = Psr::default();
+        let mut memory = Memory::new();
+        let mut running = true;
+        let mut console = BufferConsole::new(b"");
+        assert_eq!(execute(0b1101_000000000000, &mut reg, &mut psr, &mut memory, &mut running, TrapMode::Builtin, &mut console),
+            Err(VmError::ReservedInstruction(0b1101_000000000000)));
+        psr.user_mode = true;
+        assert_eq!(execute(0b1000_000000000000, &mut reg, &mut psr, &mut memory, &mut running, TrapMode::Builtin, &mut console),
+            Err(VmError::PrivilegeViolation));
+        assert_eq!(execute(0b0001_000_000_1_00001, &mut reg, &mut psr, &mut memory, &mut running, TrapMode::Builtin, &mut console), Ok(()));
+        assert!(running);
     }
 }
@@ -4,9 +4,9 @@ use crate::defs::register::*;
 
 pub fn sign_ext(mut val: u16, bit_count: i16) -> u16 {
     if (val >> (bit_count - 1)) & 1 == 1 {
-        val = val | 0xffff << bit_count;
+        val |= 0xffff << bit_count;
     }
-    return val;
+    val
 }
 
 pub fn update_flags(reg: &mut Register, dr: u16) {
@@ -17,4 +17,17 @@ pub fn update_flags(reg: &mut Register, dr: u16) {
     } else {
         reg[Reg::R_COND] = Cond_flags::FL_POS as u16;
     }
-}
\ No newline at end of file
+}
+
+/// Print the bits of an instruction, most significant first.
+pub fn print_instr(x: u16) {
+    let mut number = x;
+    let mut i = 16;
+    while i > 0 {
+        let bit = (number & 0b1000000000000000) >> 15;
+        print!("{}", bit);
+        number <<= 1;
+        i -= 1;
+    }
+    println!();
+}
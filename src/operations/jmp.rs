@@ -1,6 +1,4 @@
-use crate::defs::memory::*;
 use crate::defs::register::*;
-use crate::operations::helper::*;
 
 
 ///
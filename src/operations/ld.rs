@@ -1,3 +1,4 @@
+use crate::defs::error::VmError;
 use crate::defs::memory::*;
 use crate::defs::register::*;
 use crate::operations::helper::*;
@@ -14,12 +15,12 @@ use crate::operations::helper::*;
 /// this value to the incremented PC. The content of memory at this address is loaded
 /// into DR. the condition codes are set based on whether the value loaded is 
 /// negative, zero, or positive.
-
-pub fn op_ld(reg: &mut Register, instr: u16, memory: &Memory) {
+pub fn op_ld(reg: &mut Register, instr: u16, memory: &Memory) -> Result<(), VmError> {
     let offset = sign_ext(instr & 0b111111111, 9);               // sign extend and get offset
     let dr = (instr >> 9) & 0b111;                               // get destination register
-    reg[dr] = memory[reg[Reg::R_PC].wrapping_add(offset)];       // load from memory
+    reg[dr] = memory.read(reg[Reg::R_PC].wrapping_add(offset))?; // load from memory
     update_flags(reg, dr);
+    Ok(())
 }
 
 #[cfg(test)]
@@ -31,10 +32,10 @@ mod tests {
         let mut reg: Register = Default::default();
         reg[Reg::R_PC] = 0x3000;
         let instr: u16 = 0b0010_001_000000011;
-        let mut memory = Memory::new(65535);
+        let mut memory = Memory::new();
         memory[0x3003] = 10;
 
-        op_ld(&mut reg, instr, &memory);
+        op_ld(&mut reg, instr, &memory).unwrap();
         assert_eq!(reg[1] , 10);
     }
 }
@@ -1,3 +1,4 @@
+use crate::defs::error::VmError;
 use crate::defs::memory::*;
 use crate::defs::register::*;
 use crate::operations::helper::*;
@@ -15,11 +16,12 @@ use crate::operations::helper::*;
 /// this value to the incremented PC. What is stored in memory at this address
 /// is theaddress of the data to be loaded into DR. The condition codes are set,
 /// based onwhether the value loaded is negative, zero, or positive.
-pub fn op_ldi(reg: &mut Register, instr: u16, memory: &Memory) {
+pub fn op_ldi(reg: &mut Register, instr: u16, memory: &Memory) -> Result<(), VmError> {
     let dr = (instr >> 9) & 0b111; // get destination register
     let offset = sign_ext(instr & 0b111111111, 9); // sign extend pc offset
-    reg[dr] = memory[memory[reg[Reg::R_PC].wrapping_add(offset)]]; // load indirect.
+    reg[dr] = memory.read(memory.read(reg[Reg::R_PC].wrapping_add(offset))?)?; // load indirect.
     update_flags(reg, dr);
+    Ok(())
 }
 
 
@@ -33,10 +35,10 @@ mod tests {
         let mut reg: Register = Default::default(); // init regs
         reg[Reg::R_PC] = pc_start; // init regs
         let instr: u16 = 0b1010_001_000000001; // define instruction
-        let mut memory = Memory::new(65535); // declare memory
+        let mut memory = Memory::new(); // declare memory
         memory[0x3001] = 0x3002; // indirect pointer
         memory[0x3002] = 10; // actual data to be loaded
-        op_ldi(&mut reg, instr, &mut memory);
+        op_ldi(&mut reg, instr, &memory).unwrap();
         assert_eq!(reg[1], 10, "testing register value");
         assert_eq!(reg[Reg::R_COND], 0b001, "testing positive flags");
     }
@@ -47,14 +49,14 @@ mod tests {
         let mut reg: Register = Default::default(); // init regs
         reg[Reg::R_PC] = pc_start; // init regs
         let instr: u16 = 0b1010_001_000000001; // define instruction
-        let mut memory = Memory::new(65535); // declare memory
+        let mut memory = Memory::new(); // declare memory
         memory[0x3001] = 0x3002; // indirect pointer
         memory[0x3002] = 0b1111111111111101; // actual data to be loaded
-        op_ldi(&mut reg, instr, &mut memory);
+        op_ldi(&mut reg, instr, &memory).unwrap();
         assert_eq!(reg[Reg::R_COND], 0b100, "testing negative flags");
 
         memory[0x3002] = 0;
-        op_ldi(&mut reg, instr, &mut memory);
+        op_ldi(&mut reg, instr, &memory).unwrap();
         assert_eq!(reg[Reg::R_COND], 0b010, "testing zero flags");
     }
 }
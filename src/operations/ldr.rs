@@ -1,3 +1,4 @@
+use crate::defs::error::VmError;
 use crate::defs::memory::*;
 use crate::defs::register::*;
 use crate::operations::helper::*;
@@ -16,12 +17,13 @@ use crate::operations::helper::*;
 /// The contents of memoryat this address are loaded into DR.
 /// The condition codes are set, based on whetherthe value loaded is
 /// negative, zero, or positive
-pub fn op_ldr(reg: &mut Register, instr: u16, memory: &Memory) {
+pub fn op_ldr(reg: &mut Register, instr: u16, memory: &Memory) -> Result<(), VmError> {
     let dr = (instr >> 9) & 0b111; // get destination register
     let base_r = (instr >> 6) & 0b111; // get base_r from instr
     let offset = sign_ext(instr & 0b111111, 6); // sign extend offset
-    reg[dr] = memory[reg[base_r].wrapping_add(offset)]; // load immediate offset
+    reg[dr] = memory.read(reg[base_r].wrapping_add(offset))?; // load immediate offset
     update_flags(reg, dr);
+    Ok(())
 }
 
 #[cfg(test)]
@@ -35,9 +37,9 @@ mod tests {
         };
 
         let instr: u16 = 0b0110_000_001_000011; // define instruction
-        let mut memory = Memory::new(65535); // declare memory
+        let mut memory = Memory::new(); // declare memory
         memory[0x3004] = 10;
-        op_ldr(&mut register, instr, &memory);
+        op_ldr(&mut register, instr, &memory).unwrap();
         assert_eq!(register[0], 10, "testing register value");
         assert_eq!(register[Reg::R_COND], 0b001, "testing positive flag");
     }
@@ -49,9 +51,9 @@ mod tests {
         };
 
         let instr: u16 = 0b0110_000_001_111111; // define instruction
-        let mut memory = Memory::new(65535); // declare memory
+        let mut memory = Memory::new(); // declare memory
         memory[0x3000] = 10;
-        op_ldr(&mut register, instr, &memory);
+        op_ldr(&mut register, instr, &memory).unwrap();
         assert_eq!(register[0], 10, "testing register value");
         assert_eq!(register[Reg::R_COND], 0b001, "testing positive flag");
     }
@@ -63,9 +65,9 @@ mod tests {
         };
 
         let instr: u16 = 0b0110_000_001_111111; // define instruction
-        let mut memory = Memory::new(65535); // declare memory
+        let mut memory = Memory::new(); // declare memory
         memory[0x3000] = 0b1111111111111011;
-        op_ldr(&mut register, instr, &memory);
+        op_ldr(&mut register, instr, &memory).unwrap();
         assert_eq!(register[Reg::R_COND], 0b100, "testing positive flag");
     }
 
@@ -1,3 +1,6 @@
+//! Implementation of the LC3 instructions, one module per opcode.
+//! [`executor::execute`] decodes an instruction and runs it.
+
 pub mod traps;
 pub mod st;
 pub mod sti;
@@ -13,4 +16,5 @@ pub mod ldr;
 pub mod lea;
 pub mod not;
 pub mod str;
+pub mod rti;
 pub mod executor;
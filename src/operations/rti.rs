@@ -0,0 +1,75 @@
+use crate::defs::error::VmError;
+use crate::defs::memory::*;
+use crate::defs::psr::Psr;
+use crate::defs::register::*;
+
+
+/// Return from interrupt
+///
+/// Instruction example
+/// 1000 000000000000
+///
+/// If the processor is running in supervisor mode, the top two elements on the
+/// supervisor stack are popped and loaded into PC, PSR. If the processor is
+/// returning to user mode, the supervisor stack pointer is saved and R6 is
+/// loaded with the user stack pointer. If the processor is running in user mode,
+/// a privilege mode violation exception occurs.
+pub fn op_rti(reg: &mut Register, psr: &mut Psr, memory: &Memory) -> Result<(), VmError> {
+    if psr.user_mode {
+        return Err(VmError::PrivilegeViolation);
+    }
+    let sp = reg[Reg::R_R6];
+    reg[Reg::R_PC] = memory[sp];                                // pop PC
+    let value = memory[sp.wrapping_add(1)];                     // pop PSR
+    reg[Reg::R_R6] = sp.wrapping_add(2);
+    psr.load(reg, value);                                       // switches stacks when going back to user mode
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_op_rti(){
+        let mut reg = Register::default();
+        let mut psr = Psr::default();
+        let mut memory = Memory::new();
+        reg[Reg::R_R6] = 0x2FFE;
+        psr.saved_usp = 0xFE00 - 1;
+        memory[0x2FFE] = 0x3005;                                 // PC
+        memory[0x2FFF] = 0x8004;                                 // PSR, user mode, N
+
+        op_rti(&mut reg, &mut psr, &memory).unwrap();
+        assert_eq!(reg[Reg::R_PC], 0x3005);
+        assert!(psr.user_mode);
+        assert_eq!(reg[Reg::R_COND], 0b100);
+        assert_eq!(psr.saved_ssp, 0x3000, "supervisor stack popped and saved");
+        assert_eq!(reg[Reg::R_R6], 0xFDFF, "user stack restored");
+    }
+
+    #[test]
+    fn test_op_rti_supervisor(){
+        let mut reg = Register::default();
+        let mut psr = Psr::default();
+        let mut memory = Memory::new();
+        reg[Reg::R_R6] = 0x2FFC;
+        memory[0x2FFC] = 0x0400;
+        memory[0x2FFD] = 0x0102;                                 // PSR, supervisor, priority 1, Z
+
+        op_rti(&mut reg, &mut psr, &memory).unwrap();
+        assert_eq!(reg[Reg::R_PC], 0x0400);
+        assert!(!psr.user_mode);
+        assert_eq!(psr.priority, 1);
+        assert_eq!(reg[Reg::R_R6], 0x2FFE, "stays on the supervisor stack");
+    }
+
+    #[test]
+    fn test_op_rti_user_mode(){
+        let mut reg = Register::default();
+        let mut psr = Psr { user_mode: true, ..Psr::default() };
+        let memory = Memory::new();
+        assert_eq!(op_rti(&mut reg, &mut psr, &memory), Err(VmError::PrivilegeViolation));
+    }
+}
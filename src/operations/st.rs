@@ -1,3 +1,4 @@
+use crate::defs::error::VmError;
 use crate::defs::memory::*;
 use crate::defs::register::*;
 use crate::operations::helper::*;
@@ -12,10 +13,10 @@ use crate::operations::helper::*;
 /// The contents of the register specified by SR are stored in the memory location
 /// whose address is computer by sign extending bits 8-0 to 16 bits and adding this
 /// vlaue to the incremented PC.
-pub fn op_st(reg: & Register, instr: u16, memory: &mut Memory) {
+pub fn op_st(reg: & Register, instr: u16, memory: &mut Memory) -> Result<(), VmError> {
     let offset = sign_ext(instr & 0b111111111, 9);                // get offset
     let sr = (instr >> 9) & 0b111;                                // get source reg
-    memory[reg[Reg::R_PC].wrapping_add(offset)] = reg[sr];        // store to memory
+    memory.write(reg[Reg::R_PC].wrapping_add(offset), reg[sr])    // store to memory
 }
 
 #[cfg(test)]
@@ -28,8 +29,8 @@ mod tests {
         reg[Reg::R_PC] = 0x3000;
         reg[1] = 10;
         let instr: u16 = 0b0011_001_000000011;
-        let mut memory = Memory::new(65535);
-        op_st(&reg, instr, &mut memory);
+        let mut memory = Memory::new();
+        op_st(&reg, instr, &mut memory).unwrap();
         assert_eq!(memory[0x3003], 10);
     }
 }
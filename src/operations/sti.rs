@@ -1,3 +1,4 @@
+use crate::defs::error::VmError;
 use crate::defs::memory::*;
 use crate::defs::register::*;
 use crate::operations::helper::*;
@@ -12,9 +13,9 @@ use crate::operations::helper::*;
 /// whose address is obtained as follows: Bits [8:0] are sign-extended to 16 bits and
 /// added to the incremented PC. What is in memory at this address is the address of
 /// the location to which the data in SR is stored.
-pub fn op_sti(reg: & Register, instr: u16, memory: &mut Memory) {
+pub fn op_sti(reg: & Register, instr: u16, memory: &mut Memory) -> Result<(), VmError> {
     let offset = sign_ext(instr & 0b111111111, 9);                 // get offset
     let sr = (instr >> 9) & 0b111;                                 // get source reg
-    let address = memory[reg[Reg::R_PC].wrapping_add(offset)];
-    memory[address] = reg[sr];                                     // store indirectly
+    let address = memory.read(reg[Reg::R_PC].wrapping_add(offset))?;
+    memory.write(address, reg[sr])                                 // store indirectly
 }
@@ -1,106 +1,236 @@
-use crate::defs::traps::Traps;
+use crate::console::Console;
+use crate::defs::error::VmError;
+use crate::defs::traps::*;
 use crate::defs::register::*;
 use crate::defs::memory::*;
-use std::io::Read;
+use crate::defs::psr::Psr;
+use crate::interrupts;
 
 /// trap routines
 /// 
-/// The implementation of the traps is provided in normal rust functions 
-/// instead of redirecting the instruction flow to a pre-determined address 
-/// on the memory(like normal machines do). 
-pub fn op_trap(reg: &mut Register, instr: u16, memory: &Memory) -> bool {
+/// Instruction example
+/// 1111 0000 trapvect8
+/// 1111 0000 00100101
+///
+/// In builtin mode the implementation of the traps is provided in normal rust
+/// functions instead of redirecting the instruction flow to a pre-determined
+/// address on the memory. In authentic mode the machine does what normal
+/// machines do, R7 is loaded with the incremented PC, PSR and PC are pushed
+/// on the supervisor stack and PC is loaded from the trap vector table at
+/// trapvect8. The routine, from an OS image, runs in supervisor mode and
+/// returns with RTI.
+/// Builtin routines do their I/O through the console.
+/// Returns whether the machine keeps running.
+pub fn op_trap(reg: &mut Register, psr: &mut Psr, instr: u16, memory: &mut Memory, mode: TrapMode,
+               console: &mut dyn Console) -> Result<bool, VmError> {
+    if mode == TrapMode::Authentic {
+        reg[Reg::R_R7] = reg[Reg::R_PC];                            // save the return address
+        interrupts::trap(reg, psr, memory, instr as u8);            // jump through the vector table
+        return Ok(true);
+    }
     let mut running: bool = true;
     match Traps::from_u16(instr & 0xFF){
-        Traps::TRAP_GETC  =>  trap_getc(reg),
-        Traps::TRAP_HALT  =>  trap_halt(&mut running),
-        Traps::TRAP_IN    =>  trap_in(reg),
-        Traps::TRAP_OUT   =>  trap_out(reg),
-        Traps::TRAP_PUTS  =>  trap_puts(reg, &memory),
-        Traps::TRAP_PUTSP =>  trap_putsp(reg, &memory),
+        Some(Traps::TRAP_GETC)  =>  trap_getc(reg, memory, console)?,
+        Some(Traps::TRAP_HALT)  =>  trap_halt(&mut running, console)?,
+        Some(Traps::TRAP_IN)    =>  trap_in(reg, memory, console)?,
+        Some(Traps::TRAP_OUT)   =>  trap_out(reg, console)?,
+        Some(Traps::TRAP_PUTS)  =>  trap_puts(reg, memory, console)?,
+        Some(Traps::TRAP_PUTSP) =>  trap_putsp(reg, memory, console)?,
+        None                    =>  return Err(VmError::UnknownTrap(instr & 0xFF)),
     }
-    return running;
+    Ok(running)
 }
 
-/// GETC trap code used to get one chracter from the standard input
+/// GETC trap code used to get one chracter from the keyboard
 /// the character is saved to R0.
-fn trap_getc(reg: &mut Register){
-    let input: u16 = std::io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .map(|byte| byte as u16).unwrap();
+fn trap_getc(reg: &mut Register, memory: &mut Memory, console: &mut dyn Console) -> Result<(), VmError> {
+    let input: u16 = read_key(memory, console)?;
     reg[Reg::R_R0] = input;
+    Ok(())
+}
+
+/// read a single byte from the console, running out of input is
+/// reported as VmError::InputEof.
+pub fn read_byte(console: &mut dyn Console) -> Result<u8, VmError> {
+    match console.read_byte()? {
+        Some(byte) => Ok(byte),
+        None => Err(VmError::InputEof),
+    }
+}
+
+/// read the next key, a key already latched in KBDR by a KBSR poll
+/// comes first so polling and GETC don't lose or reorder keys.
+fn read_key(memory: &mut Memory, console: &mut dyn Console) -> Result<u16, VmError> {
+    match memory.devices.take_key() {
+        Some(key) => Ok(key),
+        None => Ok(read_byte(console)? as u16),
+    }
 }
 
 /// HALT Trap code to halt the program.
-fn trap_halt(running: &mut bool){
-    println!("HALT PROGRAM");
+fn trap_halt(running: &mut bool, console: &mut dyn Console) -> Result<(), VmError> {
+    console.write_bytes(b"HALT PROGRAM\n")?;
+    console.flush()?;
     *running = false;
+    Ok(())
 }
 
-/// IN trap code to get one character from stdin and output it to stdout
-/// after its saved to R0.
-fn trap_in(reg: &mut Register){
-    print!("Enter a character: ");
-    let input: char = std::io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .map(|byte| byte as char).unwrap();
-
-    reg[Reg::R_R0] = input as u16;
+/// IN trap code to prompt for one character on the console, the
+/// character is saved to R0.
+fn trap_in(reg: &mut Register, memory: &mut Memory, console: &mut dyn Console) -> Result<(), VmError> {
+    console.write_bytes(b"Enter a character: ")?;
+    console.flush()?;
+    reg[Reg::R_R0] = read_key(memory, console)?;
+    Ok(())
 }
 
-/// OUT trap code used to output a character to standard output.
-fn trap_out(reg: &Register){
-   print!("{}", reg[Reg::R_R0]); 
+/// OUT trap code used to output the character in R0.
+fn trap_out(reg: &Register, console: &mut dyn Console) -> Result<(), VmError> {
+    console.write_byte(reg[Reg::R_R0] as u8)?;
+    console.flush()?;
+    Ok(())
 }
 
 /// PUTS trap code used to output a null terminated string.
 /// The string displayed has its address in R0. In LC3 a character
 /// is stored in a single momory location => each character is 16 bits
 /// and not one byte
-fn trap_puts(reg: &Register, memory: &Memory){
+fn trap_puts(reg: &Register, memory: &Memory, console: &mut dyn Console) -> Result<(), VmError> {
     let mut i = reg[Reg::R_R0]; 
-    let mut c: char = 'a';
-    while c != '\0'{
-        c = (memory[i] as u8) as char;
-        print!("{}", c);
-        i += 1;
+    loop {
+        let c = memory.read_system(i);
+        if c == 0 {
+            break;
+        }
+        console.write_byte(c as u8)?;
+        i = i.wrapping_add(1);
     }
+    console.flush()?;
+    Ok(())
 }
 
-/// PUTSP trap code used to output a null terminated string to stdout
-/// the address of the string is fetched from R0. Characters are printed
-/// in a big endian format.
-fn trap_putsp(reg: &Register, memory: &Memory){
+/// PUTSP trap code used to output a null terminated string
+/// the address of the string is fetched from R0. Each word holds two
+/// characters, the low byte first.
+fn trap_putsp(reg: &Register, memory: &Memory, console: &mut dyn Console) -> Result<(), VmError> {
     let mut i: u16 = reg[Reg::R_R0];
-    while memory[i] as u8 as char != '\0' {
-        let c1: char = (memory[i] & 0xFF) as u8 as char;
-        print!("{}", c1);
-        let c2: u8 = (memory[i] >> 8) as u8;
+    loop {
+        let word = memory.read_system(i);
+        if word as u8 == 0 {
+            break;
+        }
+        console.write_byte(word as u8)?;
+        let c2: u8 = (word >> 8) as u8;
         if c2 != 0 {
-            print!("{}", c2 as char);
+            console.write_byte(c2)?;
         }
-        i += 1;
+        i = i.wrapping_add(1);
     }
+    console.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::console::BufferConsole;
     
     #[test]
     fn test_trap_halt(){
         let mut running = true;
-        trap_halt(&mut running);
-        assert_eq!(running, false);
+        let mut console = BufferConsole::new(b"");
+        trap_halt(&mut running, &mut console).unwrap();
+        assert!(!running);
+        assert_eq!(&*console.output().borrow(), b"HALT PROGRAM\n");
+    }
+
+    #[test]
+    fn test_unknown_trap(){
+        let mut register = Register::default();
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new(b"");
+        assert_eq!(op_trap(&mut register, &mut Psr::default(), 0xF026, &mut memory, TrapMode::Builtin, &mut console),
+            Err(VmError::UnknownTrap(0x26)), "not a HALT");
+        assert!(console.output().borrow().is_empty());
     }
 
     #[test]
-    fn test_trap_puts(){
+    fn test_op_trap_authentic(){
         let mut register = Register::default();
-        let memory = Memory::new(100);
-        trap_puts(&mut register, &memory);
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new(b"");
+        let mut psr = Psr { user_mode: true, saved_ssp: 0x3000, ..Psr::default() };
+        memory[0x25] = 0x0400;
+        register[Reg::R_PC] = 0x3001;
+        register[Reg::R_R6] = 0xF000;
+        register[Reg::R_COND] = 0b010;
+        let running = op_trap(&mut register, &mut psr, 0xF025, &mut memory, TrapMode::Authentic, &mut console).unwrap();
+        assert!(running);
+        assert_eq!(register[Reg::R_PC], 0x0400);
+        assert_eq!(register[Reg::R_R7], 0x3001);
+        assert!(!psr.user_mode, "the routine runs in supervisor mode");
+        assert_eq!(register[Reg::R_R6], 0x2FFE);
+        assert_eq!(memory[0x2FFF], 0x8002, "user PSR pushed");
+        assert_eq!(memory[0x2FFE], 0x3001, "return address pushed");
+        assert!(console.output().borrow().is_empty());
+    }
+
+    #[test]
+    fn test_puts_leaves_out_the_nul(){
+        let mut register = Register::default();
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new(b"");
+        trap_puts(&register, &memory, &mut console).unwrap();
+        assert!(console.output().borrow().is_empty(), "an empty string writes nothing");
+        register[Reg::R_R0] = 0x10;
+        memory[0x10] = b'a' as u16;
+        trap_puts(&register, &memory, &mut console).unwrap();
+        assert_eq!(&*console.output().borrow(), b"a", "no NUL after the string");
+    }
+
+    #[test]
+    fn test_out_prints_a_character(){
+        let mut register = Register::default();
+        let mut console = BufferConsole::new(b"");
+        register[Reg::R_R0] = b'A' as u16;
+        trap_out(&register, &mut console).unwrap();
+        assert_eq!(&*console.output().borrow(), b"A", "not the number 65");
+    }
+
+    #[test]
+    fn test_trap_output(){
+        let mut register = Register::default();
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new(b"");
+        register[Reg::R_R0] = 0x4000;
+        for (i, c) in b"hi".iter().enumerate() {
+            memory[0x4000 + i as u16] = *c as u16;
+        }
+        trap_puts(&register, &memory, &mut console).unwrap();
+        memory[0x4000] = u16::from_le_bytes(*b"ok");
+        memory[0x4001] = b'!' as u16;
+        memory[0x4002] = 0;
+        trap_putsp(&register, &memory, &mut console).unwrap();
+        register[Reg::R_R0] = b'\n' as u16;
+        trap_out(&register, &mut console).unwrap();
+        assert_eq!(&*console.output().borrow(), b"hiok!\n");
+    }
+
+    #[test]
+    fn test_trap_input(){
+        let mut register = Register::default();
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new(b"xy");
+        trap_getc(&mut register, &mut memory, &mut console).unwrap();
+        assert_eq!(register[Reg::R_R0], b'x' as u16);
+        trap_in(&mut register, &mut memory, &mut console).unwrap();
+        assert_eq!(register[Reg::R_R0], b'y' as u16);
+        assert_eq!(&*console.output().borrow(), b"Enter a character: ");
+        assert_eq!(trap_getc(&mut register, &mut memory, &mut console), Err(VmError::InputEof));
+
+        memory.devices.press_key(b'k');
+        trap_getc(&mut register, &mut memory, &mut console).unwrap();
+        assert_eq!(register[Reg::R_R0], b'k' as u16, "latched key comes first");
+        assert!(!memory.devices.key_ready());
     }
 }
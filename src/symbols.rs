@@ -0,0 +1,183 @@
+use std::fs;
+use std::io::Error;
+
+/// The symbols of the bundled LC3 operating system (lc3os.asm).
+pub const LC3OS_SYM: &str = include_str!("../lc3os.sym");
+
+/// How far past a label an address can be and still be shown relative to
+/// it, further away it's shown as a plain address.
+pub const MAX_LABEL_OFFSET: u16 = 0x100;
+
+/// Labels and their addresses, from the assembler or an lc3as `.sym` file,
+/// used to show `LOOP+3` where an address would otherwise be printed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SymbolTable {
+    symbols: Vec<(String, u16)>,                                        // in definition order
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build a table from (label, address) pairs.
+    pub fn from_pairs(pairs: &[(String, u16)]) -> Self {
+        let mut table = Self::new();
+        for (name, address) in pairs {
+            table.insert(name, *address);
+        }
+        table
+    }
+
+    /// Parse a symbol file in the lc3as format:
+    ///
+    /// ```text
+    /// // Symbol table
+    /// // Scope level 0:
+    /// //  Symbol Name       Page Address
+    /// //  ----------------  ------------
+    /// //  LOOP              3002
+    /// ```
+    ///
+    /// (a tab follows the slashes). Every line made of a label and a hex
+    /// address is an entry, headers and anything else are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut table = Self::new();
+        for line in text.lines() {
+            let line = line.trim_start().trim_start_matches("//");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [name, address] = fields[..] {
+                let label = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if let (true, Ok(address)) = (label, u16::from_str_radix(address, 16)) {
+                    table.insert(name, address);
+                }
+            }
+        }
+        table
+    }
+
+    /// Read a symbol file from disk.
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// The symbols of the bundled operating system.
+    pub fn lc3os() -> Self {
+        Self::parse(LC3OS_SYM)
+    }
+
+    /// The table in the lc3as `.sym` format.
+    pub fn to_sym(&self) -> String {
+        let mut out = String::from("// Symbol table\n// Scope level 0:\n");
+        out += "//\tSymbol Name       Page Address\n";
+        out += "//\t----------------  ------------\n";
+        for (name, address) in &self.symbols {
+            out += &format!("//\t{:<16}  {:04X}\n", name, address);
+        }
+        out += "\n";
+        out
+    }
+
+    /// Add a symbol, a label that's already known is moved to `address`.
+    pub fn insert(&mut self, name: &str, address: u16) {
+        match self.symbols.iter_mut().find(|(known, _)| known == name) {
+            Some(symbol) => symbol.1 = address,
+            None => self.symbols.push((name.to_string(), address)),
+        }
+    }
+
+    /// Add every symbol of `other`.
+    pub fn extend(&mut self, other: &SymbolTable) {
+        for (name, address) in &other.symbols {
+            self.insert(name, *address);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.symbols.iter().map(|(name, address)| (name.as_str(), *address))
+    }
+
+    /// The address of a label.
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.iter().find(|(known, _)| *known == name).map(|(_, address)| address)
+    }
+
+    /// The label at exactly `address`, the first one defined if there are several.
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.iter().find(|(_, at)| *at == address).map(|(name, _)| name)
+    }
+
+    /// `address` relative to the closest label at or before it, `LOOP` or
+    /// `LOOP+3`, None if no label is close enough.
+    pub fn label(&self, address: u16) -> Option<String> {
+        let (name, at) = self.iter()
+            .filter(|(_, at)| *at <= address && address - *at < MAX_LABEL_OFFSET)
+            .fold(None, |best: Option<(&str, u16)>, (name, at)| match best {
+                Some((_, best_at)) if best_at >= at => best,
+                _ => Some((name, at)),
+            })?;
+        Some(if at == address { name.to_string() } else { format!("{}+{}", name, address - at) })
+    }
+
+    /// `address` the way it's best shown, relative to a label if possible
+    /// and as `x3007` otherwise.
+    pub fn describe(&self, address: u16) -> String {
+        self.label(address).unwrap_or_else(|| format!("x{:04X}", address))
+    }
+}
+
+/// A number typed by the user as a word: x10, X10, 0x10, #16, 16 or -1.
+/// Labels are looked up by the caller.
+pub fn parse_number(text: &str) -> Option<u16> {
+    let (digits, radix) = match text.strip_prefix('x').or_else(|| text.strip_prefix('X')).or_else(|| text.strip_prefix("0x")) {
+        Some(hex) => (hex, 16),
+        None => (text.strip_prefix('#').unwrap_or(text), 10),
+    };
+    let n = i32::from_str_radix(digits, radix).ok()?;
+    if (-0x8000..=0xFFFF).contains(&n) { Some(n as u16) } else { None }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> SymbolTable {
+        SymbolTable::from_pairs(&[(String::from("START"), 0x3000), (String::from("LOOP"), 0x3004), (String::from("AGAIN"), 0x3004)])
+    }
+
+    #[test]
+    fn test_describe(){
+        let symbols = table();
+        assert_eq!(symbols.describe(0x3004), "LOOP");
+        assert_eq!(symbols.describe(0x3007), "LOOP+3");
+        assert_eq!(symbols.describe(0x3001), "START+1");
+        assert_eq!(symbols.describe(0x2FFF), "x2FFF");
+        assert_eq!(symbols.describe(0x3104), "x3104", "too far from LOOP");
+        assert_eq!(symbols.address("AGAIN"), Some(0x3004));
+        assert_eq!(symbols.name(0x3004), Some("LOOP"));
+    }
+
+    #[test]
+    fn test_sym_format(){
+        let text = table().to_sym();
+        assert!(text.contains("//\tLOOP              3004\n"));
+        assert_eq!(SymbolTable::parse(&text), table());
+        assert_eq!(SymbolTable::lc3os().address("TRAP_HALT"), Some(0x0241));
+    }
+
+    #[test]
+    fn test_parse_number(){
+        for text in ["x3000", "X3000", "0x3000", "#12288", "12288"] {
+            assert_eq!(parse_number(text), Some(0x3000), "{}", text);
+        }
+        assert_eq!(parse_number("-1"), Some(0xFFFF));
+        assert_eq!(parse_number("x10000"), None);
+        assert_eq!(parse_number("LOOP"), None);
+    }
+}
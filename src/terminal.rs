@@ -0,0 +1,130 @@
+//! Raw terminal mode for interactive programs.
+//!
+//! Games like 2048 or Rogue want every key as soon as it's pressed, without
+//! line buffering or echo. While a [`RawTerminal`] is alive the terminal on
+//! standard input runs in non-canonical mode with echo off. The original
+//! settings come back when it's dropped, when the process gets Ctrl-C and
+//! when a panic happens. Nothing changes when standard input isn't a TTY
+//! (a pipe, a file), so scripted runs keep working.
+
+#[cfg(unix)]
+mod imp {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Once, OnceLock};
+
+    /// Terminal settings from before raw mode, restored on the way out.
+    static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+    /// Whether the terminal is currently in raw mode.
+    static RAW: AtomicBool = AtomicBool::new(false);
+    static HOOKS: Once = Once::new();
+
+    pub fn enable() -> bool {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return false;
+            }
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return false;
+            }
+            let original = *ORIGINAL.get_or_init(|| termios);
+            install_hooks();
+
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO);           // key by key, no echo, Ctrl-C still signals
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return false;
+            }
+            RAW.store(true, Ordering::SeqCst);
+            true
+        }
+    }
+
+    /// Put the original settings back, safe to call from a signal handler.
+    pub fn restore() {
+        if RAW.swap(false, Ordering::SeqCst) {
+            if let Some(original) = ORIGINAL.get() {
+                unsafe {
+                    libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+                }
+            }
+        }
+    }
+
+    extern "C" fn on_interrupt(signal: libc::c_int) {
+        restore();
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    /// Restore the terminal on Ctrl-C (and kill), and before a panic message is printed.
+    fn install_hooks() {
+        HOOKS.call_once(|| {
+            unsafe {
+                let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                libc::signal(libc::SIGINT, handler);
+                libc::signal(libc::SIGTERM, handler);
+            }
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                restore();
+                previous(info);
+            }));
+        });
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub fn enable() -> bool {
+        false
+    }
+
+    pub fn restore() {}
+}
+
+/// Keeps the terminal in raw mode for as long as it's alive.
+pub struct RawTerminal {
+    raw: bool,
+}
+
+impl RawTerminal {
+    /// Switch the terminal to raw mode, falls back to leaving it untouched
+    /// when standard input isn't a TTY.
+    pub fn enable() -> Self {
+        RawTerminal { raw: imp::enable() }
+    }
+
+    /// Whether the terminal was actually switched to raw mode.
+    pub fn is_raw(&self) -> bool {
+        self.raw
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if self.raw {
+            imp::restore();
+        }
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_a_tty(){
+        // cargo test doesn't give the tests a terminal
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+            return;
+        }
+        let terminal = RawTerminal::enable();
+        assert!(!terminal.is_raw());
+    }
+}
@@ -0,0 +1,780 @@
+use crate::console::{Console, Input, StdConsole};
+use crate::devices::KEY_EOF;
+use crate::defs::cond_flags::Cond_flags;
+use crate::defs::error::VmError;
+use crate::defs::memory::*;
+use crate::defs::psr::*;
+use crate::interrupts::{self, Interrupt, INT_VECTOR_TABLE};
+use crate::defs::register::*;
+use crate::defs::traps::TrapMode;
+use crate::loader::{load_images, Image};
+use crate::operations::executor::execute;
+use crate::symbols::SymbolTable;
+use crate::devices::KBSR_IE;
+use crate::defs::opcode::Opcode;
+use crate::watch::{Condition, Watchpoint};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Write};
+use std::time::{Duration, Instant};
+
+/// Default program start, the beginning of user space.
+pub const PC_START: u16 = 0x3000;
+
+/// How many instructions run between two looks at the clock when a
+/// time limit is set.
+const CLOCK_INTERVAL: u64 = 1024;
+
+/// Why the machine stopped running.
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    /// the program halted.
+    Halted,
+    /// PC reached a breakpoint (or the address given to `run_until`),
+    /// the instruction at that address has not been executed yet.
+    Breakpoint(u16),
+    /// the watchpoint at this index in `watchpoints` went off, with the
+    /// access that set it off for a memory watchpoint. The instruction
+    /// that did it has been executed.
+    Watchpoint(usize, Option<Access>),
+    /// the instruction budget given to `run_for` ran out.
+    BudgetExhausted,
+    /// the run took longer than `time_limit`.
+    Timeout,
+    /// the instruction at this address branches (or jumps) to itself and
+    /// nothing can break the loop, only reported when `detect_loops` is set.
+    InfiniteLoop(u16),
+    /// an instruction failed and no exception handler is installed for it,
+    /// PC is left at the faulting instruction.
+    Fault(VmError),
+    /// the program wanted a key after the input ran out and the machine
+    /// was told to halt then, PC is left at the instruction that asked.
+    InputExhausted,
+}
+
+/// What happens when the program wants a key after the input ran out.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EofPolicy {
+    /// stop with StopReason::InputExhausted.
+    Halt,
+    /// stop with StopReason::Fault(VmError::InputEof).
+    Fault,
+    /// GETC and IN return KEY_EOF (xFFFF) in R0 and polling KBSR finds
+    /// KEY_EOF ready in KBDR, the program decides what to do.
+    Value,
+}
+
+/// The LC3 machine, registers, PSR and memory plus the state needed to run them.
+/// The machine starts in supervisor mode at priority 0 with R6 pointing
+/// at the supervisor stack and the Z flag set, traps are serviced by the
+/// builtin routines and running out of input is a fault.
+///
+/// From now on the process is fairly simple
+/// 1- load the instruction from the RAM (PC)
+/// 2- increment PC
+/// 3- inspect the opcode to determine the operation then perform it
+/// 4- goto 1
+pub struct Vm {
+    pub reg: Register,
+    pub psr: Psr,
+    pub memory: Memory,
+    pub running: bool,
+    pub trap_mode: TrapMode,
+    pub console: Box<dyn Console>,
+    pub breakpoints: HashSet<u16>,
+    /// breakpoints that only stop the machine when their condition holds.
+    pub break_conditions: HashMap<u16, Condition>,
+    /// checked after every instruction, memory accesses are only recorded
+    /// while there are some.
+    pub watchpoints: Vec<Watchpoint>,
+    pub on_eof: EofPolicy,
+    /// wall-clock limit of a single run, a run blocked waiting for a key
+    /// only notices once the key arrives.
+    pub time_limit: Option<Duration>,
+    /// stop on trivial self-loops (see StopReason::InfiniteLoop).
+    pub detect_loops: bool,
+    /// labels used to show addresses, in traces and by the debugger.
+    pub symbols: SymbolTable,
+    /// where every executed instruction is logged, if anywhere.
+    pub trace: Option<Box<dyn Write>>,
+    /// the exception handed to an OS handler that hasn't returned yet, with
+    /// the address of the instruction that raised it. The bundled OS halts
+    /// after reporting one, a handler that recovers clears it with its RTI.
+    pub exception: Option<(u16, VmError)>,
+    exception_frame: u16,                                               // R6 once the handler was entered
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    /// A machine with zeroed memory and PC at the start of user space,
+    /// talking to standard input and output.
+    pub fn new() -> Self {
+        Self::with_console(Box::new(StdConsole))
+    }
+
+    /// A machine doing its I/O through `console`.
+    pub fn with_console(console: Box<dyn Console>) -> Self {
+        let mut reg = Register::default();
+        reg[Reg::R_PC] = PC_START;
+        reg[Reg::R_R6] = SSP_START;
+        reg[Reg::R_COND] = Cond_flags::FL_ZRO as u16;
+        Self {
+            reg,
+            psr: Psr::default(),
+            memory: Memory::new(),
+            running: true,
+            trap_mode: TrapMode::Builtin,
+            console,
+            breakpoints: HashSet::new(),
+            break_conditions: HashMap::new(),
+            watchpoints: Vec::new(),
+            on_eof: EofPolicy::Fault,
+            time_limit: None,
+            detect_loops: false,
+            symbols: SymbolTable::new(),
+            trace: None,
+            exception: None,
+            exception_frame: 0,
+        }
+    }
+
+    /// Load images into memory and point PC at the origin of `images[entry]`.
+    pub fn load_images(&mut self, images: &[Image], entry: usize) -> Result<u16, Error> {
+        let origin = load_images(&mut self.memory, images, entry)?;
+        self.reg[Reg::R_PC] = origin;
+        Ok(origin)
+    }
+
+    /// Execute a single instruction, then take a pending interrupt so the
+    /// next step starts in its service routine.
+    /// Returns `None` if the machine can keep going, otherwise why it can't.
+    pub fn step(&mut self) -> Option<StopReason> {
+        if !self.running {
+            return Some(StopReason::Halted);
+        }
+        let pc = self.reg[Reg::R_PC];
+        let sp = self.reg[Reg::R_R6];
+        self.memory.user_mode = self.psr.user_mode;
+        self.memory.observe = !self.watchpoints.is_empty();
+        self.memory.take_accesses();                                    // left by an instruction that faulted
+        let mut fetched = 0;
+        let mut result = self.memory.fetch(pc)                          // fetch instruction
+            .and_then(|instr| {
+                fetched = instr;
+                self.trace(pc, instr);
+                self.reg[Reg::R_PC] = pc.wrapping_add(1);               // increment program counter
+                execute(instr, &mut self.reg, &mut self.psr, &mut self.memory, &mut self.running, self.trap_mode, self.console.as_mut())
+            });
+        if result == Err(VmError::InputEof) && self.on_eof == EofPolicy::Value {
+            self.reg[Reg::R_R0] = KEY_EOF;                              // GETC or IN found no key
+            result = Ok(());
+        }
+        let result = result.and_then(|_| self.update_devices());
+        if let Err(e) = result {
+            if e == VmError::InputEof && self.on_eof == EofPolicy::Halt {
+                self.reg[Reg::R_PC] = pc;
+                self.running = false;
+                return Some(StopReason::InputExhausted);
+            }
+            if !self.raise_exception(pc, &e) {
+                self.reg[Reg::R_PC] = pc;
+                return Some(StopReason::Fault(e));
+            }
+            self.exception = Some((pc, e));
+            self.exception_frame = self.reg[Reg::R_R6];
+        } else if self.exception.is_some() && sp == self.exception_frame
+            && matches!(Opcode::from_u16(fetched >> 12), Opcode::OP_RTI) {
+            self.exception = None;                                      // the handler returned
+        }
+        if !self.running {
+            return Some(StopReason::Halted);
+        }
+        if self.detect_loops && self.is_self_loop(pc, fetched) {
+            return Some(StopReason::InfiniteLoop(pc));
+        }
+        if let Some(int) = interrupts::pending(&self.memory.devices, &self.psr) {
+            interrupts::interrupt(&mut self.reg, &mut self.psr, &mut self.memory, int);
+        }
+        self.check_watchpoints()
+    }
+
+    /// Whether PC is at a breakpoint whose condition, if it has one, holds.
+    pub fn at_breakpoint(&self) -> bool {
+        let pc = self.reg[Reg::R_PC];
+        self.breakpoints.contains(&pc)
+            && self.break_conditions.get(&pc).is_none_or(|condition| condition.holds(&self.reg, &self.memory))
+    }
+
+    /// The first watchpoint the accesses of the last instruction (or the
+    /// state it left) set off, if any.
+    fn check_watchpoints(&mut self) -> Option<StopReason> {
+        if !self.memory.observe {
+            return None;
+        }
+        let accesses = self.memory.take_accesses();
+        for (index, watchpoint) in self.watchpoints.iter_mut().enumerate() {
+            if let Some(access) = watchpoint.check(&accesses, &self.reg, &self.memory) {
+                return Some(StopReason::Watchpoint(index, access));
+            }
+        }
+        None
+    }
+
+    /// Log the instruction about to run at `pc`, a trace that can't be
+    /// written is dropped rather than stopping the machine.
+    fn trace(&mut self, pc: u16, instr: u16) {
+        if let Some(out) = self.trace.as_mut() {
+            let label = self.symbols.label(pc).unwrap_or_default();
+            if writeln!(out, "x{:04X} {:<16} x{:04X}", pc, label, instr).is_err() {
+                self.trace = None;
+            }
+        }
+    }
+
+    /// Whether `instr`, just executed at `pc`, left the machine exactly where
+    /// it was: a BR or JMP back to itself changes neither registers nor
+    /// flags, so only a keyboard interrupt could get it out.
+    fn is_self_loop(&self, pc: u16, instr: u16) -> bool {
+        let branch = matches!(Opcode::from_u16(instr >> 12), Opcode::OP_BR | Opcode::OP_JMP);
+        branch && self.reg[Reg::R_PC] == pc && self.memory.devices.kbsr & KBSR_IE == 0
+    }
+
+    /// Raise the LC3 exception for `error`, caused by the instruction at `pc`.
+    ///
+    /// The saved PC points at the faulting instruction. Returns false when
+    /// the error isn't an exception or its vector table entry is empty
+    /// (no OS installed a handler), the caller treats it as a fault then.
+    fn raise_exception(&mut self, pc: u16, error: &VmError) -> bool {
+        let vector = match interrupts::exception_vector(error) {
+            Some(vector) => vector,
+            None => return false,
+        };
+        if self.memory[INT_VECTOR_TABLE + vector as u16] == 0 {
+            return false;
+        }
+        self.memory.devices.update();
+        self.reg[Reg::R_PC] = pc;
+        let int = Interrupt { vector, priority: self.psr.priority };
+        interrupts::interrupt(&mut self.reg, &mut self.psr, &mut self.memory, int);
+        true
+    }
+
+    /// Apply the side effects of the device register accesses the last
+    /// instruction made: print what was written to DDR, check (without
+    /// blocking) for a key when KBSR is polled or keyboard interrupts are
+    /// enabled and stop when MCR stops the clock.
+    /// Polling KBSR after the input ran out is handled according to `on_eof`.
+    fn update_devices(&mut self) -> Result<(), VmError> {
+        let devices = &mut self.memory.devices;
+        if let Some(c) = devices.take_output() {
+            self.console.write_byte(c)?;
+            self.console.flush()?;
+        }
+        if devices.wants_key() {
+            match self.console.try_read_byte()? {
+                Input::Byte(key) => devices.press_key(key),
+                Input::Waiting => {}                                    // KBSR stays clear
+                Input::Eof if !devices.kbsr_polled() => {}              // only waiting for an interrupt
+                Input::Eof if self.on_eof == EofPolicy::Value => devices.press_eof(),
+                Input::Eof => {
+                    devices.update();
+                    return Err(VmError::InputEof);
+                }
+            }
+        }
+        devices.update();
+        if !devices.clock_running() {
+            self.running = false;
+        }
+        Ok(())
+    }
+
+    /// Run until the program halts, faults or hits a breakpoint.
+    pub fn run(&mut self) -> StopReason {
+        self.run_with(None, None)
+    }
+
+    /// Run at most `budget` instructions.
+    pub fn run_for(&mut self, budget: u64) -> StopReason {
+        self.run_with(Some(budget), None)
+    }
+
+    /// Run until PC reaches `pc`, reported as a breakpoint at that address.
+    pub fn run_until(&mut self, pc: u16) -> StopReason {
+        self.run_with(None, Some(pc))
+    }
+
+    /// Breakpoints (and `until`) are checked before every instruction but
+    /// the first one, so resuming from a breakpoint doesn't stop right away.
+    fn run_with(&mut self, budget: Option<u64>, until: Option<u16>) -> StopReason {
+        let mut executed: u64 = 0;
+        let deadline = self.time_limit.map(|limit| Instant::now() + limit);
+        loop {
+            if let Some(deadline) = deadline {
+                if executed.is_multiple_of(CLOCK_INTERVAL) && Instant::now() >= deadline {
+                    return StopReason::Timeout;
+                }
+            }
+            let pc = self.reg[Reg::R_PC];
+            if executed > 0 && (until == Some(pc) || self.at_breakpoint()) {
+                return StopReason::Breakpoint(pc);
+            }
+            if budget == Some(executed) {
+                return StopReason::BudgetExhausted;
+            }
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+            executed += 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::devices::STATUS_READY;
+    use crate::watch::WatchKind;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer the test keeps a handle on.
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// x3000 ADD R0, R0, #1
+    /// x3001 ADD R1, R1, #-1
+    /// x3002 BRp x3000
+    /// x3003 HALT
+    fn countdown(n: u16) -> Vm {
+        let mut vm = Vm::new();
+        vm.load_images(&[Image::from_words(&[0x3000,
+            0b0001_000_000_1_00001,
+            0b0001_001_001_1_11111,
+            0b0000_0_0_1_111111101,
+            0xF025]).unwrap()], 0).unwrap();
+        vm.reg[Reg::R_R1] = n;
+        vm
+    }
+
+    #[test]
+    fn test_step(){
+        let mut vm = countdown(2);
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.reg[Reg::R_R0], 1);
+        assert_eq!(vm.reg[Reg::R_PC], 0x3001);
+    }
+
+    #[test]
+    fn test_run(){
+        let mut vm = countdown(3);
+        assert_eq!(vm.run(), StopReason::Halted);
+        assert_eq!(vm.reg[Reg::R_R0], 3);
+        assert_eq!(vm.step(), Some(StopReason::Halted), "stays halted");
+    }
+
+    #[test]
+    fn test_run_for(){
+        let mut vm = countdown(100);
+        assert_eq!(vm.run_for(7), StopReason::BudgetExhausted);
+        assert_eq!(vm.reg[Reg::R_R0], 3);
+        assert_eq!(vm.reg[Reg::R_PC], 0x3001);
+        assert_eq!(vm.run_for(0), StopReason::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_run_until(){
+        let mut vm = countdown(5);
+        assert_eq!(vm.run_until(0x3002), StopReason::Breakpoint(0x3002));
+        assert_eq!(vm.reg[Reg::R_R1], 4);
+        assert_eq!(vm.run_until(0x3002), StopReason::Breakpoint(0x3002), "resumes past the stop");
+        assert_eq!(vm.reg[Reg::R_R1], 3);
+    }
+
+    #[test]
+    fn test_breakpoints(){
+        let mut vm = countdown(5);
+        vm.breakpoints.insert(0x3000);
+        assert_eq!(vm.run(), StopReason::Breakpoint(0x3000));
+        assert_eq!(vm.reg[Reg::R_R0], 1);
+        vm.breakpoints.clear();
+        assert_eq!(vm.run(), StopReason::Halted);
+
+        let mut vm = countdown(5);
+        vm.breakpoints.insert(0x3000);
+        vm.break_conditions.insert(0x3000, Condition::parse("R1 == 2", &vm.symbols).unwrap());
+        assert_eq!(vm.run(), StopReason::Breakpoint(0x3000));
+        assert_eq!(vm.reg[Reg::R_R0], 3, "only once R1 got down to 2");
+    }
+
+    #[test]
+    fn test_watchpoints(){
+        let program = crate::assembler::assemble("
+            .ORIG x3000
+            LD R1, COUNT
+            ST R1, COPY
+            LDI R2, PTR
+            LEA R3, COPY
+            STR R2, R3, #0
+            LDR R4, R3, #0
+            STI R2, PTR
+            LEA R0, MSG
+            PUTS
+            HALT
+    COUNT   .FILL 3
+    COPY    .FILL 0
+    PTR     .FILL COUNT
+    MSG     .STRINGZ \"hi\"
+            .END").unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.load_images(&[program.image()], 0).unwrap();
+        vm.symbols = program.symbol_table();
+        let write = |address, value| Some(Access { address, kind: AccessKind::Write, value });
+        vm.watchpoints.push(Watchpoint::Memory { start: 0x300B, end: 0x300B, kind: WatchKind::Write, condition: None });
+        vm.watchpoints.push(Watchpoint::Memory { start: 0x300D, end: 0x300F, kind: WatchKind::Read, condition: None });
+        let condition = Condition::parse("R4 == COUNT", &vm.symbols).unwrap();
+        vm.watchpoints.push(Watchpoint::condition(condition, &vm.reg, &vm.memory));
+        vm.watchpoints.push(Watchpoint::Memory { start: 0x300A, end: 0x300A, kind: WatchKind::Access,
+            condition: Some(Condition::parse("mem[COUNT] == 0", &vm.symbols).unwrap()) });
+
+        assert_eq!(vm.run(), StopReason::Watchpoint(0, write(0x300B, 3)), "ST");
+        assert_eq!(vm.reg[Reg::R_PC], 0x3002, "after the instruction");
+        assert_eq!(vm.run(), StopReason::Watchpoint(0, write(0x300B, 3)), "STR");
+        vm.memory[0x300B] = 0x300A;
+        assert_eq!(vm.run(), StopReason::Watchpoint(2, None), "LDR made the condition true");
+        assert_eq!(vm.run(), StopReason::Watchpoint(1, Some(Access { address: 0x300D, kind: AccessKind::Read, value: b'h' as u16 })), "PUTS");
+        assert_eq!(vm.run(), StopReason::Halted, "STI wrote 3, not 0");
+    }
+
+    #[test]
+    fn test_time_limit(){
+        let mut vm = countdown(u16::MAX);
+        vm.time_limit = Some(Duration::from_millis(0));
+        assert_eq!(vm.run(), StopReason::Timeout);
+        vm.time_limit = Some(Duration::from_secs(60));
+        assert_eq!(vm.run(), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_detect_loops(){
+        // x3000 ADD R0, R0, #1
+        // x3001 BRnzp x3001
+        let mut vm = Vm::new();
+        vm.load_images(&[Image::from_words(&[0x3000,
+            0b0001_000_000_1_00001,
+            0b0000_111_111111111]).unwrap()], 0).unwrap();
+        assert_eq!(vm.run_for(100), StopReason::BudgetExhausted, "off by default");
+        vm.reg[Reg::R_PC] = 0x3000;
+        vm.detect_loops = true;
+        assert_eq!(vm.run(), StopReason::InfiniteLoop(0x3001));
+        assert_eq!(vm.reg[Reg::R_PC], 0x3001);
+
+        vm.memory.devices.kbsr |= KBSR_IE;
+        assert_eq!(vm.run_for(100), StopReason::BudgetExhausted, "an interrupt can end it");
+    }
+
+    #[test]
+    fn test_trace(){
+        let mut vm = countdown(1);
+        vm.symbols = SymbolTable::from_pairs(&[(String::from("LOOP"), 0x3000)]);
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        vm.trace = Some(Box::new(SharedBuffer(trace.clone())));
+        assert_eq!(vm.run(), StopReason::Halted);
+        let trace = String::from_utf8(trace.borrow().clone()).unwrap();
+        assert_eq!(trace.lines().collect::<Vec<_>>(), vec![
+            "x3000 LOOP             x1021",
+            "x3001 LOOP+1           x127F",
+            "x3002 LOOP+2           x03FD",
+            "x3003 LOOP+3           xF025",
+        ]);
+    }
+
+    #[test]
+    fn test_fault(){
+        let mut vm = Vm::new();
+        vm.memory[0x3000] = 0b1101_000000000000;
+        assert_eq!(vm.run(), StopReason::Fault(VmError::ReservedInstruction(0b1101_000000000000)));
+        assert_eq!(vm.reg[Reg::R_PC], 0x3000);
+    }
+
+    #[test]
+    fn test_mcr_halts(){
+        // x3000 AND R0, R0, #0
+        // x3001 STI R0, x3002
+        // x3002 .FILL xFFFE
+        let mut vm = Vm::new();
+        vm.load_images(&[Image::from_words(&[0x3000,
+            0b0101_000_000_1_00000,
+            0b1011_000_000000000,
+            0xFFFE]).unwrap()], 0).unwrap();
+        assert_eq!(vm.run(), StopReason::Halted);
+        assert_eq!(vm.reg[Reg::R_PC], 0x3002);
+    }
+
+    #[test]
+    fn test_keyboard_poll(){
+        // x3000 LDI R0, x3002   ; KBSR
+        // x3001 LDI R1, x3003   ; KBDR
+        let mut vm = Vm::new();
+        vm.load_images(&[Image::from_words(&[0x3000,
+            0b1010_000_000000001,
+            0b1010_001_000000001,
+            0xFE00,
+            0xFE02]).unwrap()], 0).unwrap();
+        vm.memory.devices.press_key(b'k');
+        assert_eq!(vm.run_for(2), StopReason::BudgetExhausted);
+        assert_eq!(vm.reg[Reg::R_R0], 0x8000);
+        assert_eq!(vm.reg[Reg::R_R1], b'k' as u16);
+        assert!(!vm.memory.devices.key_ready());
+    }
+
+    #[test]
+    fn test_keyboard_interrupt(){
+        // x3000 BRnzp x3000     ; user program spins
+        // x1000 LDI R0, x1002   ; service routine reads the key
+        // x1001 RTI
+        // x1002 .FILL xFE02
+        let mut vm = Vm::new();
+        vm.load_images(&[
+            Image::from_words(&[0x3000, 0b0000_1_1_1_111111111]).unwrap(),
+            Image::from_words(&[0x1000, 0b1010_000_000000001, 0x8000, 0xFE02]).unwrap(),
+            Image::from_words(&[0x0180, 0x1000]).unwrap(),
+        ], 0).unwrap();
+        vm.psr.load(&mut vm.reg, 0x8002);                              // user mode
+        vm.reg[Reg::R_R6] = 0xF000;
+        vm.memory.devices.kbsr |= crate::devices::KBSR_IE;
+
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.reg[Reg::R_PC], 0x3000, "nothing pending");
+
+        vm.memory.devices.press_key(b'z');
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.reg[Reg::R_PC], 0x1000, "vectored through x0180");
+        assert!(!vm.psr.user_mode);
+        assert_eq!(vm.run_for(2), StopReason::BudgetExhausted);
+        assert_eq!(vm.reg[Reg::R_R0], b'z' as u16);
+        assert_eq!(vm.reg[Reg::R_PC], 0x3000, "back in the user program");
+        assert!(vm.psr.user_mode);
+        assert_eq!(vm.reg[Reg::R_R6], 0xF000);
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.reg[Reg::R_PC], 0x3000, "key consumed, no new interrupt");
+    }
+
+    #[test]
+    fn test_exceptions(){
+        // x3000 LDI R0, x3001   ; user mode read of KBSR
+        // x3001 .FILL xFE00
+        // x3002 .FILL x8000     ; RTI
+        // x3003 .FILL xD000     ; reserved
+        let mut vm = Vm::new();
+        vm.load_images(&[
+            Image::from_words(&[0x3000, 0b1010_000_000000000, 0xFE00, 0x8000, 0xD000]).unwrap(),
+            Image::from_words(&[0x0100, 0x1000, 0x1001, 0x1002]).unwrap(),
+        ], 0).unwrap();
+        vm.psr.load(&mut vm.reg, 0x8002);
+        vm.reg[Reg::R_R6] = 0xF000;
+
+        for (pc, handler) in [(0x3000, 0x1002), (0x3002, 0x1000), (0x3003, 0x1001)] {
+            vm.reg[Reg::R_PC] = pc;
+            assert_eq!(vm.step(), None);
+            assert_eq!(vm.reg[Reg::R_PC], handler);
+            assert_eq!(vm.exception.as_ref().map(|(at, _)| *at), Some(pc), "exception recorded");
+            assert_eq!(vm.memory[0x2FFE], pc, "faulting instruction saved");
+            assert_eq!(vm.memory[0x2FFF], 0x8002, "user PSR saved");
+            assert!(!vm.psr.user_mode);
+            vm.reg[Reg::R_R6] = 0x3000;                                // pop the handler's frame
+            vm.psr.load(&mut vm.reg, 0x8002);
+        }
+
+        vm.reg[Reg::R_PC] = 0x2000;
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.reg[Reg::R_PC], 0x1002, "fetch from system space");
+        assert_eq!(vm.memory[0x2FFE], 0x2000);
+    }
+
+    #[test]
+    fn test_exception_recovered(){
+        // x0101 .FILL x1000     ; illegal opcode vector
+        // x1000 LDR R0, R6, #0  ; skip the instruction that raised it
+        // x1001 ADD R0, R0, #1
+        // x1002 STR R0, R6, #0
+        // x1003 RTI
+        // x3000 .FILL xD000     ; reserved
+        // x3001 HALT
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.load_images(&[
+            Image::from_words(&[0x3000, 0xD000, 0xF025]).unwrap(),
+            Image::from_words(&[0x0101, 0x1000]).unwrap(),
+            Image::from_words(&[0x1000, 0x6180, 0x1021, 0x7180, 0x8000]).unwrap(),
+        ], 0).unwrap();
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.exception, Some((0x3000, VmError::ReservedInstruction(0xD000))));
+        assert_eq!(vm.run_until(0x3001), StopReason::Breakpoint(0x3001));
+        assert_eq!(vm.exception, None, "cleared by the handler's RTI");
+        assert_eq!(vm.run(), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_exception_without_handler(){
+        let mut vm = Vm::new();
+        vm.psr.load(&mut vm.reg, 0x8002);
+        vm.reg[Reg::R_PC] = 0x2000;
+        assert_eq!(vm.step(), Some(StopReason::Fault(VmError::AccessViolation(0x2000))));
+    }
+
+    #[test]
+    fn test_authentic_traps(){
+        // x3000 ADD R1, R1, #5
+        // x3001 LD R0, x3004
+        // x3002 OUT
+        // x3003 HALT
+        // x3004 .FILL x0A
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        let mut vm = Vm::with_console(Box::new(console));
+        vm.trap_mode = TrapMode::Authentic;
+        vm.load_images(&[Image::from_words(&[0x3000,
+            0b0001_001_001_1_00101,
+            0b0010_000_000000010,
+            0xF021,
+            0xF025,
+            0x000A]).unwrap(), Image::lc3os()], 0).unwrap();
+        assert_eq!(vm.run_until(0x3003), StopReason::Breakpoint(0x3003));
+        assert_eq!(vm.reg[Reg::R_R1], 5, "OUT preserves registers");
+        assert_eq!(vm.reg[Reg::R_R7], 0x3003, "R7 holds the return address");
+        assert_eq!(vm.reg[Reg::R_R6], SSP_START, "RTI popped what TRAP pushed");
+        assert_eq!(vm.run(), StopReason::Halted);
+        assert!(vm.reg[Reg::R_PC] < 0x3000, "halted inside the OS");
+        assert_eq!((vm.reg[Reg::R_R0], vm.reg[Reg::R_R1]), (0x000A, 5), "HALT puts R0 and R1 back");
+        assert_eq!(&*output.borrow(), b"\nHALT PROGRAM\n");
+        assert!(!vm.memory.devices.clock_running());
+
+        // from user mode the routines run in supervisor mode and come back
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        let mut vm = Vm::with_console(Box::new(console));
+        vm.trap_mode = TrapMode::Authentic;
+        vm.load_images(&[Image::from_words(&[0x3000,
+            0b0001_001_001_1_00101,
+            0b0010_000_000000010,
+            0xF021,
+            0xF025,
+            0x000A]).unwrap(), Image::lc3os()], 0).unwrap();
+        vm.psr.load(&mut vm.reg, PSR_USER);
+        vm.reg[Reg::R_R6] = 0xFDFF;
+        assert_eq!(vm.run_until(0x3003), StopReason::Breakpoint(0x3003));
+        assert!(vm.psr.user_mode, "back in user mode");
+        assert_eq!(vm.reg[Reg::R_R6], 0xFDFF, "user stack untouched");
+        assert_eq!(vm.run(), StopReason::Halted);
+        assert_eq!(&*output.borrow(), b"\nHALT PROGRAM\n");
+    }
+
+    #[test]
+    fn test_starts_with_z_set(){
+        // x3000 BRnzp x3002
+        // x3001 .FILL xD000 (illegal)
+        // x3002 HALT
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        assert_eq!(vm.reg[Reg::R_COND], Cond_flags::FL_ZRO as u16);
+        vm.load_images(&[Image::from_words(&[0x3000, 0b0000_111_000000001, 0xD000, 0xF025]).unwrap()], 0).unwrap();
+        assert_eq!(vm.run(), StopReason::Halted, "BRnzp branches before any flag was set");
+    }
+
+    #[test]
+    fn test_console(){
+        // x3000 GETC
+        // x3001 OUT
+        // x3002 BRnzp x3000
+        let console = BufferConsole::new(b"echo");
+        let output = console.output();
+        let mut vm = Vm::with_console(Box::new(console));
+        vm.load_images(&[Image::from_words(&[0x3000, 0xF020, 0xF021, 0b0000_111_111111101]).unwrap()], 0).unwrap();
+        assert_eq!(vm.run(), StopReason::Fault(VmError::InputEof));
+        assert_eq!(&*output.borrow(), b"echo");
+    }
+
+    #[test]
+    fn test_eof_policy(){
+        // x3000 GETC
+        // x3001 BRnzp x3000
+        let program = Image::from_words(&[0x3000, 0xF020, 0b0000_111_111111110]).unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"a")));
+        vm.on_eof = EofPolicy::Halt;
+        vm.load_images(std::slice::from_ref(&program), 0).unwrap();
+        assert_eq!(vm.run(), StopReason::InputExhausted);
+        assert_eq!(vm.reg[Reg::R_R0], b'a' as u16);
+        assert_eq!(vm.reg[Reg::R_PC], 0x3000);
+        assert_eq!(vm.step(), Some(StopReason::Halted), "stays halted");
+
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.on_eof = EofPolicy::Value;
+        vm.load_images(&[program], 0).unwrap();
+        assert_eq!(vm.run_until(0x3001), StopReason::Breakpoint(0x3001));
+        assert_eq!(vm.reg[Reg::R_R0], KEY_EOF);
+    }
+
+    #[test]
+    fn test_eof_polling(){
+        // x3000 LDI R0, x3002   ; poll KBSR
+        // x3001 BRzp x3000
+        // x3002 .FILL xFE00
+        let program = Image::from_words(&[0x3000, 0b1010_000_000000001, 0b0000_011_111111110, 0xFE00]).unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.load_images(std::slice::from_ref(&program), 0).unwrap();
+        assert_eq!(vm.run(), StopReason::Fault(VmError::InputEof));
+        assert_eq!(vm.reg[Reg::R_PC], 0x3000);
+
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.on_eof = EofPolicy::Value;
+        vm.load_images(&[program], 0).unwrap();
+        assert_eq!(vm.run_until(0x3002), StopReason::Breakpoint(0x3002));
+        assert_eq!(vm.memory.devices.kbdr, KEY_EOF);
+    }
+
+    #[test]
+    fn test_poll_then_getc(){
+        // x3000 LDI R2, x3005   ; poll KBSR
+        // x3001 BRzp x3000
+        // x3002 LDI R0, x3006   ; KBDR
+        // x3003 GETC
+        // x3004 HALT
+        let console = BufferConsole::new(b"ab");
+        let mut vm = Vm::with_console(Box::new(console));
+        vm.load_images(&[Image::from_words(&[0x3000,
+            0b1010_010_000000100,
+            0b0000_011_111111110,
+            0b1010_000_000000011,
+            0xF020,
+            0xF025,
+            0xFE00,
+            0xFE02]).unwrap()], 0).unwrap();
+        assert_eq!(vm.run_until(0x3004), StopReason::Breakpoint(0x3004));
+        assert_eq!(vm.reg[Reg::R_R0], b'b' as u16);
+        assert_eq!(vm.reg[Reg::R_R2], STATUS_READY);
+        assert_eq!(vm.memory.devices.kbdr, b'a' as u16);
+    }
+
+    #[test]
+    fn test_last_address(){
+        let mut vm = Vm::new();
+        vm.memory[0xFFFF] = 0b0001_000_000_1_00001;
+        vm.reg[Reg::R_PC] = 0xFFFF;
+        assert_eq!(vm.step(), None);
+        assert_eq!(vm.reg[Reg::R_PC], 0x0000, "PC wraps around");
+    }
+}
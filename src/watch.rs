@@ -0,0 +1,269 @@
+//! Watchpoints, stopping the machine when memory is read or written or when
+//! a condition becomes true, and the conditions themselves.
+//!
+//! A condition compares two operands: `R0 == x41`, `mem[x4000] > 10` or
+//! `mem[R6] != 0`. Operands are R0-R7, PC, COND, a number, a label (its
+//! address) or `mem[...]` of any of those. Words compare as unsigned, -1
+//! is xFFFF.
+
+use crate::defs::memory::{Access, AccessKind, Memory};
+use crate::defs::register::Register;
+use crate::symbols::{parse_number, SymbolTable};
+use std::fmt;
+
+/// Comparison operators.
+const OPERATORS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Eq),
+    ("!=", Comparison::Ne),
+    ("<=", Comparison::Le),
+    (">=", Comparison::Ge),
+    ("<", Comparison::Lt),
+    (">", Comparison::Gt),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    /// an index into the registers, 8 is PC and 9 COND.
+    Register(u16),
+    Memory(Box<Operand>),
+    Number(u16),
+}
+
+impl Operand {
+    fn parse(text: &str, symbols: &SymbolTable) -> Result<Self, String> {
+        let text = text.trim();
+        if let Some(address) = text.strip_prefix("mem[").and_then(|t| t.strip_suffix(']')) {
+            return Ok(Operand::Memory(Box::new(Operand::parse(address, symbols)?)));
+        }
+        match text.to_uppercase().as_str() {
+            "PC" => return Ok(Operand::Register(8)),
+            "COND" | "CC" => return Ok(Operand::Register(9)),
+            name => {
+                if let Some(r) = name.strip_prefix('R').and_then(|r| r.parse::<u16>().ok()).filter(|r| *r < 8) {
+                    return Ok(Operand::Register(r));
+                }
+            }
+        }
+        parse_number(text)
+            .or_else(|| symbols.address(text))
+            .map(Operand::Number)
+            .ok_or_else(|| format!("{} is neither a register, mem[...], a number nor a known label", text))
+    }
+
+    fn value(&self, reg: &Register, memory: &Memory) -> u16 {
+        match self {
+            Operand::Register(r) => reg[*r],
+            Operand::Memory(address) => memory.peek(address.value(reg, memory)),
+            Operand::Number(n) => *n,
+        }
+    }
+}
+
+/// A comparison checked against the registers and memory, see the module docs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    left: Operand,
+    comparison: Comparison,
+    right: Operand,
+    text: String,                                                       // as written, to show it
+}
+
+impl Condition {
+    /// Parse `R0 == x41`, labels are looked up in `symbols`.
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<Self, String> {
+        let text = text.trim();
+        let (at, operator, comparison) = find_operator(text)
+            .ok_or_else(|| format!("{} is not a comparison, write it as R0 == x41", text))?;
+        let (left, right) = (text[..at].trim(), text[at + operator.len()..].trim());
+        if left.is_empty() || right.is_empty() {
+            return Err(format!("{} is missing an operand", text));
+        }
+        if find_operator(right).is_some() {
+            return Err(format!("{} has more than one comparison", text));
+        }
+        Ok(Condition {
+            left: Operand::parse(left, symbols)?,
+            comparison,
+            right: Operand::parse(right, symbols)?,
+            text: text.to_string(),
+        })
+    }
+
+    /// Whether the condition holds now, reading memory doesn't count as an access.
+    pub fn holds(&self, reg: &Register, memory: &Memory) -> bool {
+        let (left, right) = (self.left.value(reg, memory), self.right.value(reg, memory));
+        match self.comparison {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+}
+
+/// The leftmost operator in `text`, the longest one where two start at the
+/// same place so `<=` isn't taken for `<`.
+fn find_operator(text: &str) -> Option<(usize, &'static str, Comparison)> {
+    OPERATORS.iter()
+        .filter_map(|&(operator, comparison)| text.find(operator).map(|at| (at, operator, comparison)))
+        .min_by_key(|&(at, operator, _)| (at, std::cmp::Reverse(operator.len())))
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Which accesses a memory watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, kind: AccessKind) -> bool {
+        match self {
+            WatchKind::Read => kind == AccessKind::Read,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Access => write!(f, "access"),
+        }
+    }
+}
+
+/// What stops the machine besides breakpoints, checked after every
+/// instruction. Instruction fetches, interrupts and the debugger looking
+/// at memory aren't accesses, the data accesses of instructions and of
+/// the builtin trap routines are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watchpoint {
+    /// an access of `kind` to `start..=end`, when `condition` (if any)
+    /// holds once the instruction is done.
+    Memory { start: u16, end: u16, kind: WatchKind, condition: Option<Condition> },
+    /// `condition` going from false to true, `held` is whether it held
+    /// after the last instruction.
+    Condition { condition: Condition, held: bool },
+}
+
+impl Watchpoint {
+    /// Watch `condition`, it only stops the machine once it becomes true
+    /// so one that holds now doesn't stop it right away.
+    pub fn condition(condition: Condition, reg: &Register, memory: &Memory) -> Self {
+        let held = condition.holds(reg, memory);
+        Watchpoint::Condition { condition, held }
+    }
+
+    /// Whether the instruction that just made `accesses` set the watchpoint
+    /// off: `Some(Some(access))` with the access that did for a memory
+    /// watchpoint, `Some(None)` for a condition.
+    pub fn check(&mut self, accesses: &[Access], reg: &Register, memory: &Memory) -> Option<Option<Access>> {
+        match self {
+            Watchpoint::Memory { start, end, kind, condition } => {
+                let access = accesses.iter()
+                    .find(|access| kind.matches(access.kind) && (*start..=*end).contains(&access.address))?;
+                match condition {
+                    Some(condition) if !condition.holds(reg, memory) => None,
+                    _ => Some(Some(*access)),
+                }
+            }
+            Watchpoint::Condition { condition, held } => {
+                let was = std::mem::replace(held, condition.holds(reg, memory));
+                if *held && !was { Some(None) } else { None }
+            }
+        }
+    }
+
+    /// How the debugger lists it, `write COUNT`, `read BUFFER:10 if R0 == 0` or `R0 == x41`.
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        match self {
+            Watchpoint::Memory { start, end, kind, condition } => {
+                let mut text = format!("{} {}", kind, symbols.describe(*start));
+                if end != start {
+                    text += &format!(":{}", end.wrapping_sub(*start) as u32 + 1);
+                }
+                if let Some(condition) = condition {
+                    text += &format!(" if {}", condition);
+                }
+                text
+            }
+            Watchpoint::Condition { condition, .. } => condition.to_string(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conditions(){
+        let mut symbols = SymbolTable::new();
+        symbols.insert("COUNT", 0x4000);
+        let mut reg = Register::default();
+        let mut memory = Memory::new();
+        reg[0] = 0x41;
+        reg[6] = 0x4000;
+        memory[0x4000] = 11;
+
+        let holds = |text: &str, reg: &Register, memory: &Memory| Condition::parse(text, &symbols).unwrap().holds(reg, memory);
+        assert!(holds("R0 == x41", &reg, &memory));
+        assert!(!holds("r0!=#65", &reg, &memory));
+        assert!(holds("mem[x4000] > 10", &reg, &memory));
+        assert!(holds("mem[COUNT] <= 11", &reg, &memory));
+        assert!(holds("mem[R6] >= mem[COUNT]", &reg, &memory));
+        assert!(holds("R1 < -1", &reg, &memory), "unsigned, -1 is xFFFF");
+        assert!(holds("PC == x0", &reg, &memory));
+
+        assert_eq!(Condition::parse("R0", &symbols).unwrap_err(), "R0 is not a comparison, write it as R0 == x41");
+        assert_eq!(Condition::parse("R9 == 1", &symbols).unwrap_err(),
+            "R9 is neither a register, mem[...], a number nor a known label");
+        assert_eq!(Condition::parse("  R0 == x41 ", &symbols).unwrap().to_string(), "R0 == x41");
+        assert_eq!(Condition::parse("R0 < R1 == 2", &symbols).unwrap_err(), "R0 < R1 == 2 has more than one comparison");
+        assert!(holds("R1 <= R0", &reg, &memory), "leftmost and longest, not <");
+    }
+
+    #[test]
+    fn test_watchpoints(){
+        let symbols = SymbolTable::new();
+        let mut reg = Register::default();
+        let memory = Memory::new();
+        let read = Access { address: 0x4001, kind: AccessKind::Read, value: 7 };
+
+        let mut watchpoint = Watchpoint::Memory { start: 0x4000, end: 0x4009, kind: WatchKind::Write, condition: None };
+        assert_eq!(watchpoint.check(&[read], &reg, &memory), None);
+        let mut watchpoint = Watchpoint::Memory { start: 0x4000, end: 0x4009, kind: WatchKind::Access, condition: None };
+        assert_eq!(watchpoint.check(&[read], &reg, &memory), Some(Some(read)));
+        assert_eq!(watchpoint.describe(&symbols), "access x4000:10");
+
+        let mut watchpoint = Watchpoint::condition(Condition::parse("R0 == 1", &symbols).unwrap(), &reg, &memory);
+        assert_eq!(watchpoint.check(&[], &reg, &memory), None);
+        reg[0] = 1;
+        assert_eq!(watchpoint.check(&[], &reg, &memory), Some(None), "became true");
+        assert_eq!(watchpoint.check(&[], &reg, &memory), None, "still true");
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use virtual_machine::Image;
+
+/// Write `words` (origin first) as an image in a fresh temporary directory.
+fn image(name: &str, words: &[u16]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("virtual_machine-cli-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.obj", name));
+    std::fs::write(&path, Image::from_words(words).unwrap().to_bytes()).unwrap();
+    path
+}
+
+/// Run the binary with `args`, stdin is empty.
+fn run(args: &[&str], path: &PathBuf) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_virtual_machine"))
+        .args(args)
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+/// Run the binary with `args` and `stdin` piped in.
+fn run_with_stdin(args: &[&str], path: &PathBuf, stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_virtual_machine"))
+        .args(args)
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_exit_r0(){
+    // x3000 LD R0, x3002
+    // x3001 HALT
+    // x3002 .FILL #42
+    let path = image("exit_r0", &[0x3000, 0x2001, 0xF025, 42]);
+    assert_eq!(run(&["--exit-r0"], &path).status.code(), Some(42));
+    let output = run(&["--exit-r0", "--traps", "authentic"], &path);
+    assert_eq!(output.status.code(), Some(42), "the OS HALT keeps R0");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("HALT PROGRAM"));
+    assert_eq!(run(&[], &path).status.code(), Some(0));
+}
+
+#[test]
+fn test_exception_exit_status(){
+    // x3000 .FILL xD000 (illegal opcode)
+    let path = image("exception", &[0x3000, 0xD000]);
+    assert_eq!(run(&[], &path).status.code(), Some(1), "no handler, a fault");
+    let output = run(&["--traps", "authentic"], &path);
+    assert_eq!(output.status.code(), Some(1), "the OS reports it and halts");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("illegal opcode"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("halted after an exception at x3000"));
+}
+
+#[test]
+fn test_debug_program_input(){
+    // x3000 GETC
+    // x3001 OUT
+    // x3002 HALT
+    let path = image("debug_input", &[0x3000, 0xF020, 0xF021, 0xF025]);
+    let output = run_with_stdin(&["debug"], &path, b"continue\nk\nquit\n");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("kHALT PROGRAM"), "the program read the line after continue: {}", stdout);
+    assert!(!stdout.contains("unknown command"), "and the debugger didn't: {}", stdout);
+}
+
+#[test]
+fn test_run_only_options(){
+    let path = image("run_only", &[0x3000, 0xF025]);
+    let cases: [&[&str]; 3] = [&["debug", "--exit-r0"], &["gdb", "--max-instructions", "10"], &["debug", "--timeout", "1"]];
+    for args in cases {
+        let output = run(args, &path);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("only applies when running"));
+    }
+}